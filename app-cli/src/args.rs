@@ -1,5 +1,6 @@
 use clap::{Parser, Subcommand};
 use figment::Figment;
+use std::net::SocketAddr;
 use std::path::PathBuf;
 
 #[derive(Debug, Parser)]
@@ -26,7 +27,41 @@ impl Cli {
                     fig = fig.merge(("comm_iroh.secret_key_path", secret_key_path));
                 }
             }
-            CliCommand::Version => {}
+            CliCommand::IssueToken(args) => {
+                if let Some(ref secret_key_path) = args.secret_key_path {
+                    fig = fig.merge(("comm_iroh.secret_key_path", secret_key_path));
+                }
+            }
+            CliCommand::RunRelay(args) => {
+                if let Some(port) = args.port {
+                    fig = fig.merge(("relay_server.port", port));
+                }
+                if let Some(bind) = args.bind {
+                    fig = fig.merge(("relay_server.bind_address", bind));
+                }
+            }
+            CliCommand::Expose(args) => {
+                if let Some(ref data_store_path) = args.data_store_path {
+                    fig = fig.merge(("data_store_path", data_store_path));
+                }
+            }
+            CliCommand::Doctor(args) => {
+                if let Some(ref secret_key_path) = args.secret_key_path {
+                    fig = fig.merge(("comm_iroh.secret_key_path", secret_key_path));
+                }
+            }
+            CliCommand::Connections(args) => {
+                if let Some(port) = args.port {
+                    fig = fig.merge(("admin_api.port", port));
+                }
+            }
+            CliCommand::ExportCa(args) => {
+                if let Some(ref ca_dir) = args.ca_dir {
+                    fig = fig.merge(("peer_gateway.ca_dir", ca_dir));
+                }
+            }
+            // Connect uses its own key, never the node's
+            CliCommand::Connect(_) | CliCommand::Version => {}
         }
         fig
     }
@@ -36,6 +71,20 @@ impl Cli {
 pub enum CliCommand {
     /// Run peer
     RunPeer(RunPeerArgs),
+    /// Run a standalone iroh relay server
+    RunRelay(RunRelayArgs),
+    /// Issue a capability token granting access to one of this node's services
+    IssueToken(IssueTokenArgs),
+    /// Share a local TCP port as a service, picked up by the running node
+    Expose(ExposeArgs),
+    /// Forward a local port to a service on a remote peer
+    Connect(ConnectArgs),
+    /// Check the node's connectivity: relay, peers, signaling and service backends
+    Doctor(DoctorArgs),
+    /// List the peers connected to the running node's services
+    Connections(ConnectionsArgs),
+    /// Print the gateway's local CA certificate, to be trusted by browsers
+    ExportCa(ExportCaArgs),
     /// Show version information
     Version,
 }
@@ -47,6 +96,113 @@ pub struct RunPeerArgs {
     pub secret_key_path: Option<PathBuf>,
 }
 
+#[derive(Debug, Parser)]
+pub struct RunRelayArgs {
+    /// Port to listen on (overrides config)
+    #[arg(long)]
+    pub port: Option<u16>,
+    /// Address to listen on, e.g. 0.0.0.0 to relay for other hosts (overrides config)
+    #[arg(long)]
+    pub bind: Option<std::net::IpAddr>,
+}
+
+#[derive(Debug, Parser)]
+pub struct IssueTokenArgs {
+    /// Service the token grants access to
+    #[arg(long)]
+    pub service: String,
+    /// Token lifetime in seconds
+    #[arg(long, default_value_t = 86400)]
+    pub ttl_secs: u64,
+    /// Allowed HTTP method, repeat for several. All methods are allowed if not given
+    #[arg(long = "method", value_name = "METHOD")]
+    pub methods: Vec<String>,
+    /// Who the token is issued to, passed on for logging
+    #[arg(long)]
+    pub subject: Option<String>,
+    /// Only accept the token from this EndpointId
+    #[arg(long, value_name = "ENDPOINT_ID")]
+    pub holder: Option<String>,
+    /// Secret key file path (overrides config)
+    #[arg(long, value_name = "FILE")]
+    pub secret_key_path: Option<PathBuf>,
+}
+
+#[derive(Debug, Parser)]
+pub struct ExposeArgs {
+    /// Local address to expose, as host:port or just a port on 127.0.0.1
+    pub addr: String,
+    /// Service name, the first label of the host name clients use
+    #[arg(long)]
+    pub name: String,
+    /// Application protocol spoken by the service
+    #[arg(long, default_value = "http", value_parser = ["http", "tcp"])]
+    pub protocol: String,
+    /// Data store path (overrides config)
+    #[arg(long, value_name = "FILE")]
+    pub data_store_path: Option<PathBuf>,
+}
+
+#[derive(Debug, Parser)]
+pub struct ConnectArgs {
+    /// Remote service as <service>@<peer endpoint id>. Tickets are not accepted, give
+    /// the peer's addresses with --addr and --relay-url instead
+    pub target: String,
+    /// Local address to listen on, port 0 picks a free port
+    #[arg(long, value_name = "ADDR")]
+    pub listen: SocketAddr,
+    /// Known direct address of the peer, repeat for several. Without one the peer is
+    /// found through discovery
+    #[arg(long = "addr", value_name = "IP:PORT")]
+    pub direct_addrs: Vec<SocketAddr>,
+    /// Relay the peer is reachable through
+    #[arg(long, value_name = "URL")]
+    pub relay_url: Option<String>,
+    /// Client key file, created if missing, e.g. for tokens bound to a holder. Defaults
+    /// to a transient key, the node's own key is never used
+    #[arg(long, value_name = "FILE")]
+    pub secret_key_path: Option<PathBuf>,
+}
+
+#[derive(Debug, Parser)]
+pub struct DoctorArgs {
+    /// Also connect to this peer, to see whether the path is direct or relayed
+    #[arg(long, value_name = "ENDPOINT_ID")]
+    pub peer: Option<String>,
+    /// Known direct address of the peer, repeat for several
+    #[arg(long = "addr", value_name = "IP:PORT", requires = "peer")]
+    pub direct_addrs: Vec<SocketAddr>,
+    /// Relay the peer is reachable through
+    #[arg(long, value_name = "URL", requires = "peer")]
+    pub relay_url: Option<String>,
+    /// Print the report as JSON
+    #[arg(long)]
+    pub json: bool,
+    /// Secret key file path (overrides config)
+    #[arg(long, value_name = "FILE")]
+    pub secret_key_path: Option<PathBuf>,
+}
+
+#[derive(Debug, Parser)]
+pub struct ConnectionsArgs {
+    /// Port of the node's admin API (overrides config)
+    #[arg(long)]
+    pub port: Option<u16>,
+    /// Print the connections as JSON
+    #[arg(long)]
+    pub json: bool,
+}
+
+#[derive(Debug, Parser)]
+pub struct ExportCaArgs {
+    /// Local CA directory (overrides config). The CA is created if it does not exist yet
+    #[arg(long, value_name = "DIR")]
+    pub ca_dir: Option<PathBuf>,
+    /// Write the PEM certificate to this file instead of stdout
+    #[arg(long, value_name = "FILE")]
+    pub out: Option<PathBuf>,
+}
+
 #[cfg(test)]
 mod tests {
     use super::*;
@@ -1,13 +1,24 @@
 mod args;
 
-use anyhow::Result;
+use anyhow::{Context, Result};
 use args::Cli;
 use clap::Parser;
+use common::capability::{CapabilityClaims, issue_token, unix_now};
 use common::config::Config;
+use common::iroh_utils::load_or_create_secret_key;
 use figment::{
     Figment,
     providers::{Env, Format, Serialized, Toml},
 };
+use iroh::{EndpointAddr, EndpointId};
+use net_iroh::client::ServiceConnector;
+use peer_proxy_http::tcp::{TunnelTarget, serve_tunnel};
+use protocol_base::tracker::ConnectionInfo;
+use std::net::SocketAddr;
+use store_interface::{ServiceRecord, ServiceStore};
+use store_sqlite::SqliteStore;
+use tokio::io::{AsyncReadExt, AsyncWriteExt};
+use tokio::net::{TcpListener, TcpStream};
 
 const APP_ENV_VAR_PREFIX: &str = "SYNEROYM_";
 
@@ -31,6 +42,118 @@ async fn main() -> Result<()> {
             let node = node::LocalNode::new(conf).await?;
             node.bootstrap().await?;
         }
+        args::CliCommand::RunRelay(_) => {
+            let addr = conf.relay_server.unwrap_or_default().bind_addr();
+            relay_server::start_server(addr).await?.wait().await?;
+        }
+        args::CliCommand::IssueToken(args) => {
+            let secret_key_path = conf
+                .comm_iroh
+                .and_then(|c| c.secret_key_path)
+                .context("issuing tokens needs a persistent node key, set secret_key_path")?;
+            let secret_key = load_or_create_secret_key(&secret_key_path)?;
+            let claims = CapabilityClaims {
+                issuer: secret_key.public().to_string(),
+                subject: args.subject,
+                holder: args.holder,
+                service: args.service,
+                expires_at: unix_now() + args.ttl_secs,
+                methods: args.methods,
+            };
+            println!("{}", issue_token(&secret_key, &claims));
+        }
+        args::CliCommand::Connect(args) => {
+            let target: TunnelTarget = args.target.parse()?;
+            let peer_id = target
+                .peer
+                .context("connect needs the peer hosting the service, as <service>@<peer>")?;
+            let peer = peer_address(peer_id, args.direct_addrs, args.relay_url)?;
+
+            // Only here to dial out, under its own key so it can run next to the node
+            let iroh_conf = conf
+                .comm_iroh
+                .as_ref()
+                .context("connect needs iroh, add a [comm_iroh] section to the config")?;
+            let secret_key = match &args.secret_key_path {
+                Some(path) => Some(load_or_create_secret_key(path)?),
+                None => None,
+            };
+            let endpoint = common::iroh_utils::bind_endpoint(iroh_conf, secret_key).await?;
+            let connector = ServiceConnector::remote_only(endpoint.clone())
+                .with_tokens(conf.capability_tokens.clone());
+
+            let listener = TcpListener::bind(args.listen).await?;
+            println!("Forwarding {} to {}", listener.local_addr()?, args.target);
+            let result = serve_tunnel(listener, target.service, peer, connector).await;
+            endpoint.close().await;
+            result?;
+        }
+        args::CliCommand::Expose(args) => {
+            let backend_target = backend_address(&args.addr)?;
+            let store = SqliteStore::new(conf.data_store_path.clone())?;
+            let service = ServiceRecord {
+                service_key: args.name.clone(),
+                app_layer_protocol: args.protocol.clone(),
+                service_image_manifest_ref: format!("local-{}/{}", args.protocol, args.name),
+                backend_target: Some(backend_target.clone()),
+                access_policy: Default::default(),
+                enabled: true,
+                identity_headers: false,
+            };
+            store.upsert_service(&service).await?;
+            println!(
+                "Exposed {} ({}) -> {}, a running node picks it up within a few seconds",
+                args.name, args.protocol, backend_target
+            );
+        }
+        args::CliCommand::Doctor(args) => {
+            let peer = match args.peer {
+                Some(id) => {
+                    let id = id
+                        .parse::<EndpointId>()
+                        .with_context(|| format!("invalid peer id {}", id))?;
+                    Some(peer_address(id, args.direct_addrs, args.relay_url)?)
+                }
+                None => None,
+            };
+            let node = node::LocalNode::new(conf).await?;
+            let report = node.diagnose(peer).await?;
+            if args.json {
+                println!("{}", serde_json::to_string_pretty(&report)?);
+            } else {
+                print!("{}", report);
+            }
+            if !report.is_healthy() {
+                anyhow::bail!("some checks failed");
+            }
+        }
+        args::CliCommand::Connections(args) => {
+            let port = conf.admin_api.unwrap_or_default().port;
+            let body = admin_get(port, "/connections").await.with_context(|| {
+                format!(
+                    "admin API unreachable on port {}, is the node running with [admin_api] enabled?",
+                    port
+                )
+            })?;
+            let connections: Vec<ConnectionInfo> = serde_json::from_slice(&body)?;
+            if args.json {
+                println!("{}", serde_json::to_string_pretty(&connections)?);
+            } else {
+                print_connections(&connections);
+            }
+        }
+        args::CliCommand::ExportCa(args) => {
+            let ca_dir = conf
+                .peer_gateway
+                .and_then(|c| c.ca_dir)
+                .context("no local CA configured, set peer_gateway.ca_dir or pass --ca-dir")?;
+            let ca = peer_web_gateway::tls::LocalCa::load_or_create(&ca_dir)?;
+            match args.out {
+                Some(path) => std::fs::write(&path, ca.cert_pem())
+                    .with_context(|| format!("failed to write {:?}", path))?,
+                None => print!("{}", ca.cert_pem()),
+            }
+        }
         args::CliCommand::Version => {
             println!("Version: {}", env!("CARGO_PKG_VERSION"));
         }
@@ -38,3 +161,83 @@ async fn main() -> Result<()> {
 
     Ok(())
 }
+
+/// GET `path` from the admin API on 127.0.0.1:`port`, returning the body.
+async fn admin_get(port: u16, path: &str) -> Result<Vec<u8>> {
+    let mut stream = TcpStream::connect(("127.0.0.1", port)).await?;
+    let request = format!(
+        "GET {} HTTP/1.1\r\nHost: 127.0.0.1:{}\r\nConnection: close\r\n\r\n",
+        path, port
+    );
+    stream.write_all(request.as_bytes()).await?;
+    let mut response = Vec::new();
+    stream.read_to_end(&mut response).await?;
+
+    let end = response
+        .windows(4)
+        .position(|w| w == b"\r\n\r\n")
+        .context("malformed response from the admin API")?;
+    let head = String::from_utf8_lossy(&response[..end]);
+    let status = head.lines().next().unwrap_or_default();
+    if status.split(' ').nth(1) != Some("200") {
+        anyhow::bail!("admin API answered {}", status);
+    }
+    Ok(response.split_off(end + 4))
+}
+
+fn print_connections(connections: &[ConnectionInfo]) {
+    if connections.is_empty() {
+        println!("No peers connected");
+        return;
+    }
+    for connection in connections {
+        println!(
+            "{}  {}  rtt {:.1} ms  sent {} B  received {} B  up {}s",
+            connection.remote_id,
+            connection.path,
+            connection.rtt_ms,
+            connection.bytes_sent,
+            connection.bytes_received,
+            connection.connected_secs
+        );
+        for stream in &connection.streams {
+            println!(
+                "    {} ({})  sent {} B  received {} B  open {}s",
+                stream.service,
+                stream.app_protocol,
+                stream.bytes_sent,
+                stream.bytes_received,
+                stream.open_secs
+            );
+        }
+    }
+}
+
+/// A peer address from its id and optional direct address and relay hints.
+fn peer_address(
+    id: EndpointId,
+    direct_addrs: Vec<SocketAddr>,
+    relay_url: Option<String>,
+) -> Result<EndpointAddr> {
+    let mut peer = EndpointAddr::new(id);
+    for addr in direct_addrs {
+        peer = peer.with_ip_addr(addr);
+    }
+    if let Some(relay_url) = relay_url {
+        peer = peer.with_relay_url(relay_url.parse()?);
+    }
+    Ok(peer)
+}
+
+/// Normalize `host:port`, or a bare port on 127.0.0.1.
+fn backend_address(addr: &str) -> Result<String> {
+    if let Ok(port) = addr.parse::<u16>() {
+        return Ok(format!("127.0.0.1:{}", port));
+    }
+    match addr.rsplit_once(':') {
+        Some((host, port)) if !host.is_empty() && port.parse::<u16>().is_ok() => {
+            Ok(addr.to_string())
+        }
+        _ => anyhow::bail!("invalid address {}, expected host:port or a port", addr),
+    }
+}
@@ -26,3 +26,114 @@ fn test_cli_help() {
         .success()
         .stdout(predicate::str::contains("Usage:"));
 }
+
+#[test]
+fn test_cli_issue_token() {
+    let dir = std::env::temp_dir().join(format!("syneroym-cli-token-{}", std::process::id()));
+    std::fs::create_dir_all(&dir).unwrap();
+    let key_path = dir.join("secret");
+
+    let mut cmd = assert_cmd::cargo::cargo_bin_cmd!("syneroym-cli");
+    cmd.args(["issue-token", "--service", "demo3001", "--method", "GET"])
+        .arg("--secret-key-path")
+        .arg(&key_path)
+        .assert()
+        .success()
+        .stdout(predicate::str::starts_with("syn1."));
+
+    std::fs::remove_dir_all(&dir).unwrap();
+}
+
+#[test]
+fn test_cli_export_ca() {
+    let dir = std::env::temp_dir().join(format!("syneroym-cli-ca-{}", std::process::id()));
+
+    let mut cmd = assert_cmd::cargo::cargo_bin_cmd!("syneroym-cli");
+    cmd.arg("export-ca")
+        .arg("--ca-dir")
+        .arg(&dir)
+        .assert()
+        .success()
+        .stdout(predicate::str::starts_with("-----BEGIN CERTIFICATE-----"));
+
+    std::fs::remove_dir_all(&dir).unwrap();
+}
+
+#[test]
+fn test_cli_expose() {
+    let dir = std::env::temp_dir().join(format!("syneroym-cli-expose-{}", std::process::id()));
+    std::fs::create_dir_all(&dir).unwrap();
+    let db = dir.join("services.db");
+
+    // Exposing the same name again updates the service
+    for (addr, protocol) in [("5173", "http"), ("127.0.0.1:5432", "tcp")] {
+        let mut cmd = assert_cmd::cargo::cargo_bin_cmd!("syneroym-cli");
+        cmd.args(["expose", addr, "--name", "dev", "--protocol", protocol])
+            .arg("--data-store-path")
+            .arg(&db)
+            .assert()
+            .success()
+            .stdout(predicate::str::contains("Exposed dev"));
+    }
+
+    let mut cmd = assert_cmd::cargo::cargo_bin_cmd!("syneroym-cli");
+    cmd.args(["expose", "not-an-address", "--name", "dev"])
+        .arg("--data-store-path")
+        .arg(&db)
+        .assert()
+        .failure();
+
+    std::fs::remove_dir_all(&dir).unwrap();
+}
+
+#[test]
+fn test_cli_connect_needs_a_peer() {
+    let mut cmd = assert_cmd::cargo::cargo_bin_cmd!("syneroym-cli");
+    cmd.args(["connect", "postgres", "--listen", "127.0.0.1:0"])
+        .assert()
+        .failure()
+        .stderr(predicate::str::contains("<service>@<peer>"));
+}
+
+#[test]
+fn test_cli_doctor_json() {
+    let dir = std::env::temp_dir().join(format!("syneroym-cli-doctor-{}", std::process::id()));
+    std::fs::create_dir_all(&dir).unwrap();
+    let config = dir.join("config.toml");
+    std::fs::write(
+        &config,
+        format!(
+            "data_store_path = {:?}\n[comm_iroh]\ndisable_relays = true\n",
+            dir.join("services.db")
+        ),
+    )
+    .unwrap();
+
+    let mut cmd = assert_cmd::cargo::cargo_bin_cmd!("syneroym-cli");
+    cmd.arg("--config-file")
+        .arg(&config)
+        .args(["expose", "3001", "--name", "demo3001"])
+        .assert()
+        .success();
+
+    // Whether the checks pass depends on what runs on this machine, the report is printed
+    // either way
+    let mut cmd = assert_cmd::cargo::cargo_bin_cmd!("syneroym-cli");
+    cmd.arg("--config-file")
+        .arg(&config)
+        .args(["doctor", "--json"])
+        .assert()
+        .stdout(predicate::str::contains("\"status\": \"skipped\""))
+        .stdout(predicate::str::contains("\"service\": \"demo3001\""));
+
+    std::fs::remove_dir_all(&dir).unwrap();
+}
+
+#[test]
+fn test_cli_connections_needs_a_node() {
+    let mut cmd = assert_cmd::cargo::cargo_bin_cmd!("syneroym-cli");
+    cmd.args(["connections", "--port", "1"])
+        .assert()
+        .failure()
+        .stderr(predicate::str::contains("admin API unreachable"));
+}
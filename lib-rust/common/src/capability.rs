@@ -0,0 +1,178 @@
+//! Signed capability tokens for delegated, time-limited service access.
+//!
+//! A token is `syn1.<claims>.<signature>`, both parts base64url encoded. The claims are
+//! JSON and the signature is the node's ed25519 signature over `syn1.<claims>`, so the
+//! node that issued a token can verify it offline with its own public key.
+
+use base64::Engine;
+use base64::engine::general_purpose::URL_SAFE_NO_PAD;
+use iroh::{EndpointId, SecretKey, Signature};
+use serde::{Deserialize, Serialize};
+use std::time::{SystemTime, UNIX_EPOCH};
+use thiserror::Error;
+
+const TOKEN_PREFIX: &str = "syn1";
+
+#[derive(Debug, Error)]
+pub enum TokenError {
+    #[error("malformed capability token")]
+    Malformed,
+    #[error("capability token has an invalid signature")]
+    BadSignature,
+    #[error("capability token was issued by another node")]
+    WrongIssuer,
+    #[error("capability token expired")]
+    Expired,
+    #[error("capability token is for service {0}")]
+    WrongService(String),
+    #[error("capability token is bound to another peer")]
+    WrongHolder,
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct CapabilityClaims {
+    /// EndpointId of the node that issued and signed the token.
+    pub issuer: String,
+    /// Who the token was handed to, for logging and for backends.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub subject: Option<String>,
+    /// If set, only this EndpointId may present the token.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub holder: Option<String>,
+    /// Service the token grants access to.
+    pub service: String,
+    /// Expiry, in seconds since the unix epoch.
+    pub expires_at: u64,
+    /// Allowed HTTP methods, empty means any method.
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub methods: Vec<String>,
+}
+
+impl CapabilityClaims {
+    pub fn allows_method(&self, method: &str) -> bool {
+        self.methods.is_empty() || self.methods.iter().any(|m| m.eq_ignore_ascii_case(method))
+    }
+}
+
+pub fn unix_now() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map(|d| d.as_secs())
+        .unwrap_or_default()
+}
+
+/// Sign `claims` with the node key. The issuer is always set to the signing node.
+pub fn issue_token(secret_key: &SecretKey, claims: &CapabilityClaims) -> String {
+    let claims = CapabilityClaims {
+        issuer: secret_key.public().to_string(),
+        ..claims.clone()
+    };
+    // Serializing plain strings and numbers cannot fail
+    let payload = serde_json::to_vec(&claims).expect("claims serialize");
+    let signed = format!("{}.{}", TOKEN_PREFIX, URL_SAFE_NO_PAD.encode(payload));
+    let signature = secret_key.sign(signed.as_bytes());
+    format!(
+        "{}.{}",
+        signed,
+        URL_SAFE_NO_PAD.encode(signature.to_bytes())
+    )
+}
+
+/// Verify a token against the issuing node's id, returning its claims if it is valid at
+/// `now` (unix seconds). Service and holder checks are left to the caller.
+pub fn verify_token(
+    token: &str,
+    issuer: &EndpointId,
+    now: u64,
+) -> Result<CapabilityClaims, TokenError> {
+    let (signed, signature) = token.rsplit_once('.').ok_or(TokenError::Malformed)?;
+    let (prefix, payload) = signed.split_once('.').ok_or(TokenError::Malformed)?;
+    if prefix != TOKEN_PREFIX {
+        return Err(TokenError::Malformed);
+    }
+
+    let signature: [u8; Signature::LENGTH] = URL_SAFE_NO_PAD
+        .decode(signature)
+        .map_err(|_| TokenError::Malformed)?
+        .try_into()
+        .map_err(|_| TokenError::Malformed)?;
+    issuer
+        .verify(signed.as_bytes(), &Signature::from_bytes(&signature))
+        .map_err(|_| TokenError::BadSignature)?;
+
+    let payload = URL_SAFE_NO_PAD
+        .decode(payload)
+        .map_err(|_| TokenError::Malformed)?;
+    let claims: CapabilityClaims =
+        serde_json::from_slice(&payload).map_err(|_| TokenError::Malformed)?;
+    if !claims.issuer.eq_ignore_ascii_case(&issuer.to_string()) {
+        return Err(TokenError::WrongIssuer);
+    }
+    if claims.expires_at <= now {
+        return Err(TokenError::Expired);
+    }
+    Ok(claims)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn claims() -> CapabilityClaims {
+        CapabilityClaims {
+            issuer: String::new(),
+            subject: Some("alice".to_string()),
+            holder: None,
+            service: "demo3001".to_string(),
+            expires_at: 1_000,
+            methods: vec!["GET".to_string()],
+        }
+    }
+
+    #[test]
+    fn test_issue_and_verify() {
+        let key = SecretKey::generate(&mut rand::rng());
+        let token = issue_token(&key, &claims());
+
+        let verified = verify_token(&token, &key.public(), 999).unwrap();
+        assert_eq!(verified.issuer, key.public().to_string());
+        assert_eq!(verified.service, "demo3001");
+        assert!(verified.allows_method("get"));
+        assert!(!verified.allows_method("POST"));
+
+        assert!(matches!(
+            verify_token(&token, &key.public(), 1_000),
+            Err(TokenError::Expired)
+        ));
+    }
+
+    #[test]
+    fn test_rejects_forged_tokens() {
+        let key = SecretKey::generate(&mut rand::rng());
+        let other = SecretKey::generate(&mut rand::rng());
+
+        let token = issue_token(&other, &claims());
+        assert!(matches!(
+            verify_token(&token, &key.public(), 0),
+            Err(TokenError::BadSignature)
+        ));
+
+        // Swap in claims for another service, keeping the original signature
+        let token = issue_token(&key, &claims());
+        let (_, signature) = token.rsplit_once('.').unwrap();
+        let mut tampered = claims();
+        tampered.service = "admin".to_string();
+        tampered.issuer = key.public().to_string();
+        let payload = URL_SAFE_NO_PAD.encode(serde_json::to_vec(&tampered).unwrap());
+        let forged = format!("{}.{}.{}", TOKEN_PREFIX, payload, signature);
+        assert!(matches!(
+            verify_token(&forged, &key.public(), 0),
+            Err(TokenError::BadSignature)
+        ));
+
+        assert!(matches!(
+            verify_token("garbage", &key.public(), 0),
+            Err(TokenError::Malformed)
+        ));
+    }
+}
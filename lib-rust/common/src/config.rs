@@ -1,4 +1,5 @@
 use serde::{Deserialize, Serialize};
+use std::net::{IpAddr, Ipv4Addr, SocketAddr};
 use std::path::PathBuf;
 
 #[derive(Deserialize, Serialize)]
@@ -17,6 +18,21 @@ pub struct Config {
     pub peer_gateway: Option<PeerGatewayConfig>,
     /// Signaling Server configuration
     pub signaling_server: Option<SignalingServerConfig>,
+    /// Embedded iroh relay configuration
+    #[serde(default)]
+    pub relay_server: Option<RelayServerConfig>,
+    /// Capability tokens attached by the local proxies when tunneling to a service
+    #[serde(default)]
+    pub capability_tokens: Vec<CapabilityTokenConfig>,
+    /// SOCKS5 proxy for `*.syneroym` names
+    #[serde(default)]
+    pub socks_proxy: Option<SocksProxyConfig>,
+    /// Local ports tunneled as raw TCP to remote services
+    #[serde(default)]
+    pub tcp_tunnels: Vec<TcpTunnelConfig>,
+    /// Local admin API, reporting live connections
+    #[serde(default)]
+    pub admin_api: Option<AdminApiConfig>,
 }
 
 impl Default for Config {
@@ -29,14 +45,43 @@ impl Default for Config {
             data_store_path: PathBuf::from("syneroym_data.db"),
             peer_gateway: Some(PeerGatewayConfig::default()),
             signaling_server: Some(SignalingServerConfig::default()),
+            relay_server: Some(RelayServerConfig::default()),
+            capability_tokens: vec![],
+            socks_proxy: Some(SocksProxyConfig::default()),
+            tcp_tunnels: vec![],
+            admin_api: Some(AdminApiConfig::default()),
         }
     }
 }
 
+#[derive(Deserialize, Serialize, Clone, Debug)]
+pub struct CapabilityTokenConfig {
+    /// Service the token grants access to
+    pub service: String,
+    /// EndpointId of the node hosting the service. If not provided, the token is
+    /// presented to any node serving a service of that name.
+    pub peer: Option<String>,
+    /// The signed token, as printed by `syneroym-cli issue-token`
+    pub token: String,
+}
+
+#[derive(Deserialize, Serialize, Clone, Debug)]
+pub struct TcpTunnelConfig {
+    /// Port to listen on, on 127.0.0.1
+    pub local_port: u16,
+    /// Remote service as `<service>@<peer endpoint id>`. Without a peer, the service is
+    /// looked up on this node.
+    pub target: String,
+}
+
 #[derive(Deserialize, Serialize, Clone)]
 pub struct PeerGatewayConfig {
     pub enabled: bool,
     pub port: u16,
+    /// Directory holding the gateway's local CA, generated on first run. When set, the
+    /// gateway terminates https itself. Export the CA with `syneroym-cli export-ca`.
+    #[serde(default)]
+    pub ca_dir: Option<PathBuf>,
 }
 
 impl Default for PeerGatewayConfig {
@@ -44,6 +89,7 @@ impl Default for PeerGatewayConfig {
         Self {
             enabled: false,
             port: 8001,
+            ca_dir: None,
         }
     }
 }
@@ -63,19 +109,136 @@ impl Default for SignalingServerConfig {
     }
 }
 
+#[derive(Deserialize, Serialize, Clone)]
+pub struct SocksProxyConfig {
+    pub enabled: bool,
+    pub port: u16,
+}
+
+impl Default for SocksProxyConfig {
+    fn default() -> Self {
+        Self {
+            enabled: false,
+            port: 1080,
+        }
+    }
+}
+
+#[derive(Deserialize, Serialize, Clone)]
+pub struct AdminApiConfig {
+    pub enabled: bool,
+    /// Port on 127.0.0.1
+    pub port: u16,
+}
+
+impl Default for AdminApiConfig {
+    fn default() -> Self {
+        Self {
+            enabled: false,
+            port: 8002,
+        }
+    }
+}
+
+#[derive(Deserialize, Serialize, Clone)]
+pub struct RelayServerConfig {
+    pub enabled: bool,
+    pub port: u16,
+    /// Address to listen on. Defaults to 127.0.0.1, set it to 0.0.0.0 to relay for other
+    /// hosts. Any node can use the relay once it is reachable.
+    #[serde(default)]
+    pub bind_address: Option<IpAddr>,
+}
+
+impl RelayServerConfig {
+    pub fn bind_addr(&self) -> SocketAddr {
+        let ip = self.bind_address.unwrap_or(IpAddr::V4(Ipv4Addr::LOCALHOST));
+        SocketAddr::new(ip, self.port)
+    }
+}
+
+impl Default for RelayServerConfig {
+    fn default() -> Self {
+        Self {
+            enabled: false,
+            port: 3340,
+            bind_address: None,
+        }
+    }
+}
+
 #[derive(Deserialize, Serialize, Default)]
 pub struct IrohCommConfig {
     /// Path to the secret key file for the Iroh node identity.
-    /// If not provided, a temporary identity may be generated or a default location used.
+    /// The key is generated on first run and reused afterwards. If not provided, a
+    /// transient identity is generated on every start.
     pub secret_key_path: Option<PathBuf>,
     /// Optional custom Relay URL to use. If None, the default relay map is used.
     pub relay_url: Option<String>,
+    /// Find peers on the local network through mDNS, in addition to the usual discovery.
+    #[serde(default)]
+    pub local_discovery: bool,
+    /// Disable relay servers (and the internet based address lookup that relies on them),
+    /// so that only direct connections are made. Combine with `local_discovery` for LANs
+    /// without internet access.
+    #[serde(default)]
+    pub disable_relays: bool,
     /// Optional port to bind the Iroh RPC to.
     pub rpc_port: Option<u16>,
 }
 
-#[derive(Deserialize, Serialize, Default)]
+#[derive(Deserialize, Serialize)]
 pub struct WebRtcCommConfig {
     /// URL of the signaling server
     pub signaling_server_url: Option<String>,
+    /// STUN and TURN servers used to gather ICE candidates, by the node and by browsers
+    /// loading the gateway page. Defaults to a public STUN server.
+    #[serde(default = "default_ice_servers")]
+    pub ice_servers: Vec<IceServerConfig>,
+    /// Only gather host candidates, ignoring `ice_servers`. For networks without internet
+    /// access, where gathering would stall on unreachable servers.
+    #[serde(default)]
+    pub host_candidates_only: bool,
+}
+
+impl Default for WebRtcCommConfig {
+    fn default() -> Self {
+        Self {
+            signaling_server_url: None,
+            ice_servers: default_ice_servers(),
+            host_candidates_only: false,
+        }
+    }
+}
+
+impl WebRtcCommConfig {
+    /// The ICE servers to use, none in host candidates only mode.
+    pub fn effective_ice_servers(&self) -> &[IceServerConfig] {
+        if self.host_candidates_only {
+            &[]
+        } else {
+            &self.ice_servers
+        }
+    }
+}
+
+fn default_ice_servers() -> Vec<IceServerConfig> {
+    vec![IceServerConfig {
+        urls: vec!["stun:stun.l.google.com:19302".to_string()],
+        username: None,
+        credential: None,
+    }]
+}
+
+/// An ICE server, with the field names of the browser's `RTCIceServer`.
+#[derive(Deserialize, Serialize, Clone, Debug, PartialEq, Eq)]
+pub struct IceServerConfig {
+    /// `stun:`, `turn:` or `turns:` URLs of the server
+    pub urls: Vec<String>,
+    /// TURN user name
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub username: Option<String>,
+    /// TURN password. Note that it is also served to browsers in the gateway page
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub credential: Option<String>,
 }
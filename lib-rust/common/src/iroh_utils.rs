@@ -1,12 +1,24 @@
-use anyhow::Result;
+use crate::config::IrohCommConfig;
+use anyhow::{Context, Result};
+use iroh::discovery::mdns::MdnsDiscovery;
 use iroh::{
-    Endpoint, RelayMap, RelayMode, RelayUrl,
+    Endpoint, EndpointId, RelayMap, RelayMode, RelayUrl, SecretKey,
     endpoint::{RecvStream, SendStream},
 };
+use std::fs;
+use std::io::Write;
+use std::path::Path;
 use std::pin::Pin;
+use std::str::FromStr;
 use std::task::{self, Poll};
 use tokio::io::{AsyncRead, AsyncWrite, ReadBuf};
-use tracing::debug;
+use tracing::{debug, info, warn};
+
+/// Object-safe alias for bidirectional byte streams, so tunnels can be handed around
+/// regardless of the underlying transport.
+pub trait AsyncStream: AsyncRead + AsyncWrite + Unpin + Send {}
+
+impl<T: AsyncRead + AsyncWrite + Unpin + Send> AsyncStream for T {}
 
 /// A wrapper around Iroh's SendStream and RecvStream that implements
 /// tokio::io::AsyncRead and tokio::io::AsyncWrite.
@@ -64,12 +76,125 @@ impl AsyncWrite for IrohStream {
     }
 }
 
-pub async fn bind_endpoint(iroh_relay_url: Option<String>) -> Result<Endpoint> {
-    let mut builder = Endpoint::builder();
-    if let Some(url_str) = iroh_relay_url {
+/// Bind the node's endpoint according to its iroh configuration.
+pub async fn bind_endpoint(
+    config: &IrohCommConfig,
+    secret_key: Option<SecretKey>,
+) -> Result<Endpoint> {
+    let mut builder = if config.disable_relays {
+        if config.relay_url.is_some() {
+            warn!("Relays are disabled, ignoring relay_url");
+        }
+        // Without relays the default DNS discovery has nothing to publish
+        Endpoint::empty_builder(RelayMode::Disabled)
+    } else if let Some(url_str) = &config.relay_url {
         let url = url_str.parse::<RelayUrl>()?;
-        builder = builder.relay_mode(RelayMode::Custom(RelayMap::from_iter(vec![url])));
+        Endpoint::builder().relay_mode(RelayMode::Custom(RelayMap::from_iter(vec![url])))
+    } else {
+        Endpoint::builder()
+    };
+    if let Some(secret_key) = secret_key {
+        builder = builder.secret_key(secret_key);
+    }
+    if config.local_discovery {
+        info!("Local network discovery enabled");
+        builder = builder.discovery(MdnsDiscovery::builder());
     }
     let endpoint = builder.bind().await?;
     Ok(endpoint)
 }
+
+/// Encode an EndpointId as a host name label.
+///
+/// The hex form is 64 characters, one more than a DNS label allows, so host names use
+/// lowercase base32 instead. `EndpointId::from_str` accepts both forms.
+pub fn endpoint_id_label(id: &EndpointId) -> String {
+    data_encoding::BASE32_NOPAD
+        .encode(id.as_bytes())
+        .to_ascii_lowercase()
+}
+
+/// Load the node identity from `path`, generating and saving a new one on first run.
+///
+/// The key is stored hex encoded and, on unix, is only readable by the current user.
+pub fn load_or_create_secret_key(path: &Path) -> Result<SecretKey> {
+    if path.exists() {
+        let contents = fs::read_to_string(path)
+            .with_context(|| format!("failed to read secret key from {:?}", path))?;
+        let secret_key = SecretKey::from_str(contents.trim())
+            .with_context(|| format!("invalid secret key in {:?}", path))?;
+        debug!("Loaded node identity from {:?}", path);
+        return Ok(secret_key);
+    }
+
+    let secret_key = SecretKey::generate(&mut rand::rng());
+    if let Some(parent) = path.parent()
+        && !parent.as_os_str().is_empty()
+    {
+        fs::create_dir_all(parent)?;
+    }
+
+    let mut options = fs::OpenOptions::new();
+    options.write(true).create_new(true);
+    #[cfg(unix)]
+    {
+        use std::os::unix::fs::OpenOptionsExt;
+        options.mode(0o600);
+    }
+    let mut file = options
+        .open(path)
+        .with_context(|| format!("failed to create secret key file {:?}", path))?;
+    let encoded: String = secret_key
+        .to_bytes()
+        .iter()
+        .map(|b| format!("{:02x}", b))
+        .collect();
+    file.write_all(encoded.as_bytes())?;
+
+    info!(
+        "Generated new node identity {} at {:?}",
+        secret_key.public(),
+        path
+    );
+    Ok(secret_key)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_secret_key_is_persisted() {
+        let dir = std::env::temp_dir().join(format!("syneroym-key-{}", std::process::id()));
+        let path = dir.join("secret");
+        let _ = fs::remove_dir_all(&dir);
+
+        let created = load_or_create_secret_key(&path).unwrap();
+        let loaded = load_or_create_secret_key(&path).unwrap();
+        assert_eq!(created.public(), loaded.public());
+
+        #[cfg(unix)]
+        {
+            use std::os::unix::fs::PermissionsExt;
+            let mode = fs::metadata(&path).unwrap().permissions().mode();
+            assert_eq!(mode & 0o777, 0o600);
+        }
+
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn test_endpoint_id_label_roundtrip() {
+        let id = SecretKey::generate(&mut rand::rng()).public();
+        let label = endpoint_id_label(&id);
+        assert!(label.len() <= 63);
+
+        let host = format!("demo3001.{}.localhost:3000", label);
+        let peer = crate::protocol_utils::extract_peer_from_host(&host).unwrap();
+        assert_eq!(EndpointId::from_str(&peer).unwrap(), id);
+        assert_eq!(
+            crate::protocol_utils::extract_peer_from_host("demo3001.localhost"),
+            None
+        );
+    }
+}
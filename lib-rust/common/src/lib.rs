@@ -1,4 +1,6 @@
+pub mod capability;
 pub mod config;
 pub mod iroh_utils;
+pub mod metrics;
 pub mod protocol_utils;
 pub mod utils;
@@ -0,0 +1,390 @@
+//! Process wide usage metrics, exported in the Prometheus text format.
+//!
+//! Bytes are counted as they flow, stream counts and durations once the stream ends.
+//! Everything is labelled with the side of the tunnel this node is on: `server` for
+//! streams served to peers, `client` for streams the local proxies opened to peers.
+//! Byte directions are seen from this node, `in` is what the remote peer sent.
+
+use std::collections::BTreeMap;
+use std::fmt::Write as _;
+use std::pin::Pin;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{Arc, LazyLock, Mutex};
+use std::task::{Context, Poll};
+use std::time::{Duration, Instant};
+use tokio::io::{AsyncRead, AsyncWrite, ReadBuf};
+
+const STREAM_BYTES: &str = "syneroym_stream_bytes_total";
+const STREAMS: &str = "syneroym_streams_total";
+const STREAM_DURATION: &str = "syneroym_stream_duration_seconds";
+const BACKEND_CONNECT_FAILURES: &str = "syneroym_backend_connect_failures_total";
+
+/// Metric families in export order, with their type and help text.
+const FAMILIES: [(&str, &str, &str); 4] = [
+    (
+        STREAM_BYTES,
+        "counter",
+        "Bytes carried by service streams, by side, service, peer and direction.",
+    ),
+    (
+        STREAMS,
+        "counter",
+        "Service streams, by side, service and peer.",
+    ),
+    (
+        STREAM_DURATION,
+        "histogram",
+        "How long service streams stayed open, by side and service.",
+    ),
+    (
+        BACKEND_CONNECT_FAILURES,
+        "counter",
+        "Failed connections to service backends, by service.",
+    ),
+];
+
+/// Upper bounds of the duration histogram buckets, in seconds.
+const DURATION_BUCKETS: [f64; 12] = [
+    0.01, 0.05, 0.1, 0.5, 1.0, 5.0, 10.0, 30.0, 60.0, 300.0, 1800.0, 3600.0,
+];
+
+/// Peer label of streams from callers the transport cannot identify.
+pub const ANONYMOUS_PEER: &str = "anonymous";
+
+/// Which end of a tunnel this node is.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Side {
+    /// The node serves the stream from one of its backends.
+    Server,
+    /// A local proxy opened the stream to a peer.
+    Client,
+}
+
+impl Side {
+    fn as_str(self) -> &'static str {
+        match self {
+            Side::Server => "server",
+            Side::Client => "client",
+        }
+    }
+}
+
+type Labels = Vec<(&'static str, String)>;
+
+#[derive(Debug, Default)]
+struct Histogram {
+    buckets: [u64; DURATION_BUCKETS.len()],
+    sum: f64,
+    count: u64,
+}
+
+#[derive(Debug, Default)]
+struct Registry {
+    /// Shared with the streams feeding them, so bytes show up while streams are open
+    counters: BTreeMap<(&'static str, Labels), Arc<AtomicU64>>,
+    histograms: BTreeMap<(&'static str, Labels), Histogram>,
+}
+
+impl Registry {
+    fn counter(&mut self, family: &'static str, labels: Labels) -> Arc<AtomicU64> {
+        self.counters.entry((family, labels)).or_default().clone()
+    }
+}
+
+static REGISTRY: LazyLock<Mutex<Registry>> = LazyLock::new(Default::default);
+
+fn stream_labels(side: Side, service: &str, peer: &str, direction: Option<&str>) -> Labels {
+    let mut labels = vec![
+        ("side", side.as_str().to_string()),
+        ("service", service.to_string()),
+        ("peer", peer.to_string()),
+    ];
+    if let Some(direction) = direction {
+        labels.push(("direction", direction.to_string()));
+    }
+    labels
+}
+
+/// Record a finished stream. Its bytes are counted as they flow, through
+/// [`ByteCounters::metered`].
+pub fn record_stream(side: Side, service: &str, peer: &str, duration: Duration) {
+    let mut registry = REGISTRY.lock().unwrap();
+    registry
+        .counter(STREAMS, stream_labels(side, service, peer, None))
+        .fetch_add(1, Ordering::Relaxed);
+
+    // Durations are not split by peer, to keep the number of series down
+    let histogram = registry
+        .histograms
+        .entry((
+            STREAM_DURATION,
+            vec![
+                ("side", side.as_str().to_string()),
+                ("service", service.to_string()),
+            ],
+        ))
+        .or_default();
+    let secs = duration.as_secs_f64();
+    for (bucket, bound) in histogram.buckets.iter_mut().zip(DURATION_BUCKETS) {
+        if secs <= bound {
+            *bucket += 1;
+        }
+    }
+    histogram.sum += secs;
+    histogram.count += 1;
+}
+
+/// Record a failed connection to the backend of `service`.
+pub fn record_backend_failure(service: &str) {
+    let labels = vec![("service", service.to_string())];
+    REGISTRY
+        .lock()
+        .unwrap()
+        .counter(BACKEND_CONNECT_FAILURES, labels)
+        .fetch_add(1, Ordering::Relaxed);
+}
+
+/// All metrics in the Prometheus text exposition format.
+pub fn render() -> String {
+    let registry = REGISTRY.lock().unwrap();
+    let mut out = String::new();
+    for (family, kind, help) in FAMILIES {
+        let _ = writeln!(out, "# HELP {} {}", family, help);
+        let _ = writeln!(out, "# TYPE {} {}", family, kind);
+        for ((_, labels), value) in registry.counters.iter().filter(|((n, _), _)| *n == family) {
+            let value = value.load(Ordering::Relaxed);
+            let _ = writeln!(out, "{}{} {}", family, format_labels(labels, None), value);
+        }
+        for ((_, labels), histogram) in registry
+            .histograms
+            .iter()
+            .filter(|((n, _), _)| *n == family)
+        {
+            for (count, bound) in histogram.buckets.iter().zip(DURATION_BUCKETS) {
+                let le = bound.to_string();
+                let _ = writeln!(
+                    out,
+                    "{}_bucket{} {}",
+                    family,
+                    format_labels(labels, Some(&le)),
+                    count
+                );
+            }
+            let _ = writeln!(
+                out,
+                "{}_bucket{} {}",
+                family,
+                format_labels(labels, Some("+Inf")),
+                histogram.count
+            );
+            let labels = format_labels(labels, None);
+            let _ = writeln!(out, "{}_sum{} {}", family, labels, histogram.sum);
+            let _ = writeln!(out, "{}_count{} {}", family, labels, histogram.count);
+        }
+    }
+    out
+}
+
+fn format_labels(labels: &Labels, le: Option<&str>) -> String {
+    let mut pairs: Vec<String> = labels
+        .iter()
+        .map(|(name, value)| format!("{}=\"{}\"", name, escape_label(value)))
+        .collect();
+    if let Some(le) = le {
+        pairs.push(format!("le=\"{}\"", le));
+    }
+    format!("{{{}}}", pairs.join(","))
+}
+
+fn escape_label(value: &str) -> String {
+    value
+        .replace('\\', "\\\\")
+        .replace('"', "\\\"")
+        .replace('\n', "\\n")
+}
+
+/// Bytes passed through a [`Counted`] stream so far.
+#[derive(Debug, Default)]
+pub struct ByteCounters {
+    sent: AtomicU64,
+    received: AtomicU64,
+    /// Exported totals the bytes are added to as well, `in` and `out`.
+    totals: Option<(Arc<AtomicU64>, Arc<AtomicU64>)>,
+}
+
+impl ByteCounters {
+    /// Counters that also add to the exported bytes of `service` and `peer` as the bytes
+    /// flow. Received bytes count as `in`.
+    pub fn metered(side: Side, service: &str, peer: &str) -> Self {
+        let mut registry = REGISTRY.lock().unwrap();
+        let bytes_in =
+            registry.counter(STREAM_BYTES, stream_labels(side, service, peer, Some("in")));
+        let bytes_out = registry.counter(
+            STREAM_BYTES,
+            stream_labels(side, service, peer, Some("out")),
+        );
+        Self {
+            totals: Some((bytes_in, bytes_out)),
+            ..Default::default()
+        }
+    }
+
+    fn add_sent(&self, n: u64) {
+        self.sent.fetch_add(n, Ordering::Relaxed);
+        if let Some((_, bytes_out)) = &self.totals {
+            bytes_out.fetch_add(n, Ordering::Relaxed);
+        }
+    }
+
+    fn add_received(&self, n: u64) {
+        self.received.fetch_add(n, Ordering::Relaxed);
+        if let Some((bytes_in, _)) = &self.totals {
+            bytes_in.fetch_add(n, Ordering::Relaxed);
+        }
+    }
+
+    /// Bytes written to the stream.
+    pub fn sent(&self) -> u64 {
+        self.sent.load(Ordering::Relaxed)
+    }
+
+    /// Bytes read from the stream.
+    pub fn received(&self) -> u64 {
+        self.received.load(Ordering::Relaxed)
+    }
+}
+
+/// A stream counting the bytes read from and written to it.
+pub struct Counted<S> {
+    inner: S,
+    counters: Arc<ByteCounters>,
+}
+
+impl<S> Counted<S> {
+    pub fn new(inner: S, counters: Arc<ByteCounters>) -> Self {
+        Self { inner, counters }
+    }
+}
+
+impl<S: AsyncRead + Unpin> AsyncRead for Counted<S> {
+    fn poll_read(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &mut ReadBuf<'_>,
+    ) -> Poll<std::io::Result<()>> {
+        let before = buf.filled().len();
+        let poll = Pin::new(&mut self.inner).poll_read(cx, buf);
+        let read = (buf.filled().len() - before) as u64;
+        self.counters.add_received(read);
+        poll
+    }
+}
+
+impl<S: AsyncWrite + Unpin> AsyncWrite for Counted<S> {
+    fn poll_write(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &[u8],
+    ) -> Poll<std::io::Result<usize>> {
+        let poll = Pin::new(&mut self.inner).poll_write(cx, buf);
+        if let Poll::Ready(Ok(n)) = poll {
+            self.counters.add_sent(n as u64);
+        }
+        poll
+    }
+
+    fn poll_flush(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<std::io::Result<()>> {
+        Pin::new(&mut self.inner).poll_flush(cx)
+    }
+
+    fn poll_shutdown(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<std::io::Result<()>> {
+        Pin::new(&mut self.inner).poll_shutdown(cx)
+    }
+}
+
+/// `copy_bidirectional` between a local client and a stream to `peer`, recording the
+/// stream whether it ends cleanly or not. Returns the bytes sent to and received from
+/// the peer.
+pub async fn copy_metered<A, B>(
+    client: &mut A,
+    remote: &mut B,
+    side: Side,
+    service: &str,
+    peer: &str,
+) -> std::io::Result<(u64, u64)>
+where
+    A: AsyncRead + AsyncWrite + Unpin + ?Sized,
+    B: AsyncRead + AsyncWrite + Unpin + ?Sized,
+{
+    let counters = Arc::new(ByteCounters::metered(side, service, peer));
+    let mut remote = Counted::new(remote, counters.clone());
+    let started = Instant::now();
+    let result = tokio::io::copy_bidirectional(client, &mut remote).await;
+    record_stream(side, service, peer, started.elapsed());
+    result.map(|_| (counters.sent(), counters.received()))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use tokio::io::{AsyncReadExt, AsyncWriteExt};
+
+    #[tokio::test]
+    async fn test_streams_are_exported() {
+        let (mut client, mut client_end) = tokio::io::duplex(1024);
+        let (mut remote, mut remote_end) = tokio::io::duplex(1024);
+        let peer = tokio::spawn(async move {
+            let mut buf = [0u8; 4];
+            remote_end.read_exact(&mut buf).await.unwrap();
+            remote_end.write_all(b"pong!").await.unwrap();
+        });
+        client.write_all(b"ping").await.unwrap();
+        client.shutdown().await.unwrap();
+
+        let (sent, received) = copy_metered(
+            &mut client_end,
+            &mut remote,
+            Side::Client,
+            "metrics-test",
+            "p\"1",
+        )
+        .await
+        .unwrap();
+        peer.await.unwrap();
+        assert_eq!((sent, received), (4, 5));
+        record_backend_failure("metrics-test");
+
+        let text = render();
+        assert!(text.contains("# TYPE syneroym_stream_duration_seconds histogram"));
+        assert!(text.contains(
+            "syneroym_stream_bytes_total{side=\"client\",service=\"metrics-test\",peer=\"p\\\"1\",direction=\"in\"} 5"
+        ));
+        assert!(text.contains(
+            "syneroym_streams_total{side=\"client\",service=\"metrics-test\",peer=\"p\\\"1\"} 1"
+        ));
+        assert!(text.contains(
+            "syneroym_stream_duration_seconds_count{side=\"client\",service=\"metrics-test\"} 1"
+        ));
+        assert!(
+            text.contains("syneroym_backend_connect_failures_total{service=\"metrics-test\"} 1")
+        );
+    }
+
+    #[tokio::test]
+    async fn test_bytes_are_exported_while_streaming() {
+        let (stream, mut remote) = tokio::io::duplex(1024);
+        let counters = Arc::new(ByteCounters::metered(Side::Server, "live-test", "p2"));
+        let mut stream = Counted::new(stream, counters.clone());
+        stream.write_all(b"hello").await.unwrap();
+        let mut buf = [0u8; 5];
+        remote.read_exact(&mut buf).await.unwrap();
+
+        // Still open, nothing recorded as finished yet
+        let text = render();
+        assert!(text.contains(
+            "syneroym_stream_bytes_total{side=\"server\",service=\"live-test\",peer=\"p2\",direction=\"out\"} 5"
+        ));
+        assert!(!text.contains("syneroym_streams_total{side=\"server\",service=\"live-test\""));
+        assert_eq!(counters.sent(), 5);
+    }
+}
@@ -69,3 +69,51 @@ pub fn extract_service_from_host(host: &str) -> Result<String> {
         Err(anyhow!("service name not found in host: {}", host))
     }
 }
+
+/// Domains under which `<service>.<peer>.<domain>` names a service on a peer.
+const PEER_DOMAINS: [&str; 2] = ["localhost", "syneroym"];
+
+/// Extract the peer label from a `<service>.<peer>.localhost` or
+/// `<service>.<peer>.syneroym` host name. Other hosts, including those with only a
+/// service label (e.g. `demo3001.localhost`), name no peer.
+pub fn extract_peer_from_host(host: &str) -> Option<String> {
+    let hostname = host.split(':').next().unwrap_or(host);
+    match hostname.split('.').collect::<Vec<_>>()[..] {
+        [service, peer, domain]
+            if !service.is_empty()
+                && !peer.is_empty()
+                && PEER_DOMAINS.iter().any(|d| d.eq_ignore_ascii_case(domain)) =>
+        {
+            Some(peer.to_string())
+        }
+        _ => None,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_only_peer_domains_name_a_peer() {
+        assert_eq!(
+            extract_peer_from_host("demo.abc.localhost:3000"),
+            Some("abc".to_string())
+        );
+        assert_eq!(
+            extract_peer_from_host("demo.abc.syneroym"),
+            Some("abc".to_string())
+        );
+        for host in [
+            "demo.localhost",
+            "localhost:3000",
+            "127.0.0.1:3000",
+            "app.localhost.localdomain",
+            "demo.abc.example.com",
+            "a.demo.abc.localhost",
+            ".abc.localhost",
+        ] {
+            assert_eq!(extract_peer_from_host(host), None, "{}", host);
+        }
+    }
+}
@@ -0,0 +1,179 @@
+use anyhow::{Result, anyhow};
+use common::config::CapabilityTokenConfig;
+use common::iroh_utils::{AsyncStream, IrohStream};
+use iroh::endpoint::Connection;
+use iroh::{Endpoint, EndpointAddr, EndpointId};
+use protocol_base::SYNEROYM_ALPN;
+use protocol_base::handshake::StreamHandshake;
+use std::collections::HashMap;
+use std::sync::{Arc, Mutex};
+use tracing::debug;
+
+use crate::LocalStreams;
+
+/// A cached connection, guarded by an async mutex so that concurrent first requests to
+/// the same target wait for a single dial.
+type ConnectionSlot = Arc<tokio::sync::Mutex<Option<Connection>>>;
+
+/// Caches one QUIC connection per remote node so that many tunnels (e.g. all the assets
+/// of a page load) share a single handshake.
+#[derive(Debug, Clone, Default)]
+pub struct ConnectionPool {
+    connections: Arc<Mutex<HashMap<EndpointId, ConnectionSlot>>>,
+}
+
+impl ConnectionPool {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Return a live connection to `target`, dialing a new one if needed.
+    pub async fn get(&self, endpoint: &Endpoint, target: EndpointAddr) -> Result<Connection> {
+        let slot = self
+            .connections
+            .lock()
+            .unwrap()
+            .entry(target.id)
+            .or_default()
+            .clone();
+
+        let mut cached = slot.lock().await;
+        if let Some(connection) = cached.as_ref()
+            && connection.close_reason().is_none()
+        {
+            return Ok(connection.clone());
+        }
+
+        debug!("Dialing new connection to {}", target.id.fmt_short());
+        let id = target.id;
+        let connection = match endpoint.connect(target, SYNEROYM_ALPN).await {
+            Ok(connection) => connection,
+            Err(e) => {
+                // Unreachable nodes leave no slot behind
+                let mut connections = self.connections.lock().unwrap();
+                if connections
+                    .get(&id)
+                    .is_some_and(|current| Arc::ptr_eq(current, &slot))
+                {
+                    connections.remove(&id);
+                }
+                return Err(e.into());
+            }
+        };
+        *cached = Some(connection.clone());
+        Ok(connection)
+    }
+
+    /// Number of remote nodes with a cached connection slot.
+    pub fn len(&self) -> usize {
+        self.connections.lock().unwrap().len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
+    /// Drop the cached connection to `target`, if any.
+    pub fn evict(&self, target: &EndpointId) {
+        self.connections.lock().unwrap().remove(target);
+    }
+}
+
+/// Opens service streams to any node, including this one.
+#[derive(Debug, Clone)]
+pub struct ServiceConnector {
+    endpoint: Endpoint,
+    /// Serves streams targeting this endpoint, if it has services of its own.
+    local: Option<LocalStreams>,
+    pool: ConnectionPool,
+    /// Capability tokens to present when requesting services.
+    tokens: Arc<Vec<CapabilityTokenConfig>>,
+}
+
+impl ServiceConnector {
+    pub fn new(endpoint: Endpoint, local: LocalStreams) -> Self {
+        Self {
+            endpoint,
+            local: Some(local),
+            pool: ConnectionPool::new(),
+            tokens: Arc::default(),
+        }
+    }
+
+    /// A connector for an endpoint that serves nothing itself and only dials out.
+    pub fn remote_only(endpoint: Endpoint) -> Self {
+        Self {
+            endpoint,
+            local: None,
+            pool: ConnectionPool::new(),
+            tokens: Arc::default(),
+        }
+    }
+
+    /// Present these capability tokens when requesting the services they are for.
+    pub fn with_tokens(mut self, tokens: Vec<CapabilityTokenConfig>) -> Self {
+        self.tokens = Arc::new(tokens);
+        self
+    }
+
+    pub fn endpoint(&self) -> &Endpoint {
+        &self.endpoint
+    }
+
+    pub fn pool(&self) -> &ConnectionPool {
+        &self.pool
+    }
+
+    /// Build the handshake requesting `service_id` on `target`, attaching a configured
+    /// capability token for it if there is one.
+    pub fn handshake_for(
+        &self,
+        target: &EndpointId,
+        service_id: &str,
+        app_protocol: &str,
+    ) -> StreamHandshake {
+        let mut request = StreamHandshake::new(service_id, app_protocol);
+        let target = target.to_string();
+        request.auth_token = self
+            .tokens
+            .iter()
+            .find(|t| {
+                t.service == service_id
+                    && t.peer
+                        .as_deref()
+                        .is_none_or(|peer| peer.eq_ignore_ascii_case(&target))
+            })
+            .map(|t| t.token.clone());
+        request
+    }
+
+    /// Open a new bi-directional stream to `target`, reusing a pooled connection.
+    pub async fn open_stream(&self, target: EndpointAddr) -> Result<Box<dyn AsyncStream>> {
+        // An endpoint cannot dial itself, our own services are served in-process
+        if target.id == self.endpoint.id() {
+            let local = self
+                .local
+                .as_ref()
+                .ok_or_else(|| anyhow!("this endpoint serves no services"))?;
+            return Ok(Box::new(local.open()));
+        }
+
+        let target_id = target.id;
+        let connection = self.pool.get(&self.endpoint, target.clone()).await?;
+        let (send, recv) = match connection.open_bi().await {
+            Ok(streams) => streams,
+            Err(e) => {
+                // The pooled connection went away between the liveness check and now
+                debug!(
+                    "Pooled connection to {} failed: {}",
+                    target_id.fmt_short(),
+                    e
+                );
+                self.pool.evict(&target_id);
+                let connection = self.pool.get(&self.endpoint, target).await?;
+                connection.open_bi().await?
+            }
+        };
+        Ok(Box::new(IrohStream::new(send, recv)))
+    }
+}
@@ -1,22 +1,37 @@
 use anyhow::Result;
 use common::config::Config;
-use common::iroh_utils::IrohStream;
+use common::iroh_utils::{IrohStream, load_or_create_secret_key};
 use iroh::{
-    endpoint::{Connection, RecvStream, SendStream},
+    Endpoint, EndpointId,
+    endpoint::Connection,
     protocol::{AcceptError, ProtocolHandler as IrohProtocolHandler, Router},
 };
 use n0_error::AnyError;
 use n0_error::e;
+use protocol_base::dispatch::StreamDispatcher;
+use protocol_base::routing::{Caller, RoutingTable};
+use protocol_base::tracker::{ConnectionHandle, ConnectionTracker};
 use protocol_base::{ProtocolHandler, SYNEROYM_ALPN};
 use std::sync::Arc;
-use tokio::io::AsyncReadExt;
-use tokio::net::TcpStream;
-use tracing::{debug, error, info};
+use tokio::io::{AsyncRead, AsyncWrite, DuplexStream};
+use tracing::{debug, info};
+
+pub mod client;
+
+/// The running iroh side of a node.
+pub struct IrohNode {
+    pub router: Router,
+    /// The peer connections the router accepted.
+    pub tracker: ConnectionTracker,
+    /// Entry point for streams that originate on this node itself.
+    pub local: LocalStreams,
+}
 
 pub async fn init(
     config: &Config,
     handlers: Vec<Arc<dyn ProtocolHandler>>,
-) -> Result<Option<Router>> {
+    routes: RoutingTable,
+) -> Result<Option<IrohNode>> {
     for handler in &handlers[..] {
         debug!(" - {}", handler.protocol_id());
     }
@@ -24,12 +39,26 @@ pub async fn init(
     // Initialize Iroh if configured
     if let Some(iroh_config) = &config.comm_iroh {
         debug!("Initializing Iroh communication...");
-        if let Some(secret) = &iroh_config.secret_key_path {
-            debug!("Using secret key at: {:?}", secret);
-        }
+        let secret_key = match &iroh_config.secret_key_path {
+            Some(path) => {
+                debug!("Using secret key at: {:?}", path);
+                Some(load_or_create_secret_key(path)?)
+            }
+            None => {
+                info!("No secret_key_path configured, using a transient node identity");
+                None
+            }
+        };
 
-        let iroh_relay_url = iroh_config.relay_url.clone();
-        let router = start_accept_side(handlers, iroh_relay_url).await?;
+        let endpoint = common::iroh_utils::bind_endpoint(iroh_config, secret_key).await?;
+        let tracker = ConnectionTracker::new(Some(endpoint.clone()));
+        let proxy = ServiceProxy {
+            dispatcher: StreamDispatcher::new(handlers, routes, endpoint.id()),
+            node_id: endpoint.id(),
+            tracker: tracker.clone(),
+        };
+        let router = start_accept_side(endpoint, proxy.clone());
+        info!("Iroh node id: {}", router.endpoint().id());
 
         info!(
             "Iroh listening on ALPN: {:?}",
@@ -41,30 +70,53 @@ pub async fn init(
         // Actually, let's just return the router. The caller can wait if they want, or just hold it.
         // router.endpoint().online().await;
 
-        return Ok(Some(router));
+        return Ok(Some(IrohNode {
+            router,
+            tracker,
+            local: LocalStreams { proxy },
+        }));
     }
     Ok(None)
 }
 
 // Taken from the Iroh echo example: https://github.com/n0-computer/iroh/blob/main/iroh/examples/echo.rs
-async fn start_accept_side(
-    handlers: Vec<Arc<dyn ProtocolHandler>>,
-    iroh_relay_url: Option<String>,
-) -> Result<Router> {
-    let endpoint = common::iroh_utils::bind_endpoint(iroh_relay_url).await?;
-
+fn start_accept_side(endpoint: Endpoint, proxy: ServiceProxy) -> Router {
     // Build our protocol handler and add our protocol, identified by its ALPN, and spawn the endpoint.
-    let router = Router::builder(endpoint)
-        .accept(SYNEROYM_ALPN, ServiceProxy { handlers })
-        .spawn();
-
-    Ok(router)
+    Router::builder(endpoint)
+        .accept(SYNEROYM_ALPN, proxy)
+        .spawn()
 }
 
 #[derive(Debug, Clone)]
 struct ServiceProxy {
-    #[allow(dead_code)]
-    handlers: Vec<Arc<dyn ProtocolHandler>>,
+    dispatcher: StreamDispatcher,
+    /// This node's own id, callers with this id are treated as the owner.
+    node_id: EndpointId,
+    tracker: ConnectionTracker,
+}
+
+/// Serves streams opened by this node against its own services.
+///
+/// An iroh endpoint cannot dial itself, so local proxies targeting this node hand their
+/// streams over in-process instead.
+#[derive(Debug, Clone)]
+pub struct LocalStreams {
+    proxy: ServiceProxy,
+}
+
+impl LocalStreams {
+    /// Open a stream that is served exactly like an incoming iroh stream.
+    pub fn open(&self) -> DuplexStream {
+        let (local, remote) = tokio::io::duplex(64 * 1024);
+        let proxy = self.proxy.clone();
+        let caller = Caller::peer(self.proxy.node_id.to_string(), true);
+        tokio::spawn(async move {
+            if let Err(e) = handle_stream(remote, &proxy, caller, None).await {
+                debug!("local stream error: {e:?}");
+            }
+        });
+        local
+    }
 }
 
 impl IrohProtocolHandler for ServiceProxy {
@@ -72,72 +124,235 @@ impl IrohProtocolHandler for ServiceProxy {
         // We can get the remote's endpoint id from the connection.
         let endpoint_id = connection.remote_id();
         debug!("accepted connection from {endpoint_id}");
+        // Peers are authenticated by the QUIC handshake, so the id can be trusted for
+        // access control
+        let caller = Caller::peer(endpoint_id.to_string(), endpoint_id == self.node_id);
+        let guard = self.tracker.track(&connection);
 
-        // We expect the connecting peer to open a single bi-directional stream.
-        let (send, recv) = connection.accept_bi().await?;
-
-        let e = handle_stream((send, recv)).await;
-
-        // Wait until the remote closes the connection, which it does once it
-        // received the response.
-        connection.closed().await;
+        // Peers multiplex many tunnels over one connection, serve each stream concurrently
+        // until the remote closes the connection.
+        loop {
+            let (send, recv) = match connection.accept_bi().await {
+                Ok(streams) => streams,
+                Err(e) => {
+                    debug!("connection from {endpoint_id} closed: {e}");
+                    break;
+                }
+            };
 
-        match e {
-            Ok(_) => Ok(()),
-            Err(_) => e,
+            let proxy = self.clone();
+            let caller = caller.clone();
+            let handle = guard.handle();
+            tokio::spawn(async move {
+                let stream = IrohStream::new(send, recv);
+                if let Err(e) = handle_stream(stream, &proxy, caller, Some(handle)).await {
+                    debug!("stream from {endpoint_id} failed: {e:?}");
+                }
+            });
         }
+
+        Ok(())
     }
 }
 
-async fn handle_stream((mut send, mut recv): (SendStream, RecvStream)) -> Result<(), AcceptError> {
-    // --- Read service name ---
-    let name_len = recv.read_u8().await?;
-    let mut name_buf = vec![0u8; name_len as usize];
-    if let Err(e) = recv.read_exact(&mut name_buf).await {
-        return Err(e!(AcceptError::User {
-            source: AnyError::from_std(e)
-        }));
-    };
-
-    let service = match String::from_utf8(name_buf) {
-        Ok(s) => s,
-        Err(e) => {
-            return Err(e!(AcceptError::User {
-                source: AnyError::from_std(e)
-            }));
-        }
-    };
-    let backend_addr = match service.as_str() {
-        "demo3001" => "127.0.0.1:3001",
-        "demo3002" => "127.0.0.1:3002",
-        _ => {
-            match send.write_all(b"HTTP/1.1 404 Not Found\r\n\r\n").await {
-                Ok(o) => return Ok(o),
-                Err(e) => {
-                    return Err(e!(AcceptError::User {
-                        source: AnyError::from_std(e)
-                    }));
-                }
-            };
-        }
-    };
-
-    // --- Connect to backend HTTP server ---
-    let mut backend = TcpStream::connect(backend_addr).await?;
-
-    // --- Tunnel data ---
-    let mut iroh_stream = IrohStream::new(send, recv);
-    match tokio::io::copy_bidirectional(&mut backend, &mut iroh_stream).await {
-        Ok((client_to_backend, backend_to_client)) => {
-            info!(
-                "--> wrote to service {} bytes, <-- wrote back to iroh {} bytes",
-                client_to_backend, backend_to_client
-            );
+fn user_error<E: std::error::Error + Send + Sync + 'static>(e: E) -> AcceptError {
+    e!(AcceptError::User {
+        source: AnyError::from_std(e)
+    })
+}
+
+async fn handle_stream<S: AsyncRead + AsyncWrite + Unpin + Send + 'static>(
+    stream: S,
+    proxy: &ServiceProxy,
+    caller: Caller,
+    connection: Option<ConnectionHandle>,
+) -> Result<(), AcceptError> {
+    // Streams of peer connections are listed by the tracker while they are forwarded
+    proxy
+        .dispatcher
+        .serve_with(stream, caller, |service, counters| {
+            connection.as_ref().map(|connection| {
+                connection.track_stream(&service.service_key, &service.app_layer_protocol, counters)
+            })
+        })
+        .await
+        .map_err(user_error)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::client::ServiceConnector;
+    use iroh::{EndpointAddr, RelayMode};
+    use protocol_base::handshake::{self, HandshakeError, HandshakeStatus, StreamHandshake};
+    use std::net::{Ipv4Addr, SocketAddr, SocketAddrV4};
+    use store_interface::ServiceRecord;
+    use tokio::io::{AsyncReadExt, AsyncWriteExt};
+    use tokio::net::TcpListener;
+
+    async fn loopback_endpoint() -> Endpoint {
+        Endpoint::empty_builder(RelayMode::Disabled)
+            .bind_addr_v4(SocketAddrV4::new(Ipv4Addr::LOCALHOST, 0))
+            .bind()
+            .await
+            .unwrap()
+    }
+
+    async fn echo_backend() -> SocketAddr {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        tokio::spawn(async move {
+            while let Ok((mut socket, _)) = listener.accept().await {
+                tokio::spawn(async move {
+                    let (mut read, mut write) = socket.split();
+                    let _ = tokio::io::copy(&mut read, &mut write).await;
+                });
+            }
+        });
+        addr
+    }
+
+    #[tokio::test]
+    async fn test_streams_share_pooled_connection() {
+        let backend = echo_backend().await;
+        let routes = RoutingTable::from_services(&[ServiceRecord {
+            service_key: "echo".to_string(),
+            app_layer_protocol: "tcp".to_string(),
+            service_image_manifest_ref: "local-tcp/echo".to_string(),
+            backend_target: Some(backend.to_string()),
+            access_policy: Default::default(),
+            enabled: true,
+            identity_headers: false,
+        }]);
+        let server = loopback_endpoint().await;
+        let proxy = ServiceProxy {
+            dispatcher: StreamDispatcher::new(vec![], routes, server.id()),
+            node_id: server.id(),
+            tracker: ConnectionTracker::new(Some(server.clone())),
+        };
+
+        let server_addr = EndpointAddr::new(server.id()).with_ip_addr(server.bound_sockets()[0]);
+        let router = Router::builder(server)
+            .accept(SYNEROYM_ALPN, proxy.clone())
+            .spawn();
+
+        let client = loopback_endpoint().await;
+        let connector = ServiceConnector::new(
+            client,
+            LocalStreams {
+                proxy: proxy.clone(),
+            },
+        );
+
+        let mut streams = Vec::new();
+        for i in 0..3u8 {
+            let mut stream = connector.open_stream(server_addr.clone()).await.unwrap();
+            handshake::request_service(&mut stream, &StreamHandshake::new("echo", "tcp"))
+                .await
+                .unwrap();
+            stream.write_all(&[i]).await.unwrap();
+            assert_eq!(stream.read_u8().await.unwrap(), i);
+            // Keep the streams open so they are served concurrently
+            streams.push(stream);
         }
-        Err(e) => {
-            error!("stream error: {e:?}");
+
+        let mut missing = connector.open_stream(server_addr.clone()).await.unwrap();
+        let err = handshake::request_service(&mut missing, &StreamHandshake::new("nope", "tcp"))
+            .await
+            .unwrap_err();
+        assert!(matches!(
+            err,
+            HandshakeError::Rejected {
+                status: HandshakeStatus::UnknownService,
+                ..
+            }
+        ));
+
+        let connection = connector
+            .pool()
+            .get(connector.endpoint(), server_addr.clone())
+            .await
+            .unwrap();
+        assert_eq!(connector.pool().len(), 1);
+        assert!(connection.close_reason().is_none());
+
+        // The rejected stream is not listed
+        let connections = proxy.tracker.connections();
+        assert_eq!(connections.len(), 1);
+        assert_eq!(
+            connections[0].remote_id,
+            connector.endpoint().id().to_string()
+        );
+        assert_eq!(connections[0].streams.len(), 3);
+        for stream in &connections[0].streams {
+            assert_eq!(stream.service, "echo");
+            assert_eq!(stream.bytes_received, 1);
         }
+
+        router.shutdown().await.unwrap();
     }
 
-    Ok(())
+    #[tokio::test]
+    async fn test_failed_dials_leave_no_pool_slot() {
+        let client = loopback_endpoint().await;
+        let pool = crate::client::ConnectionPool::new();
+        // No relay and no discovery, so a bare id cannot be dialed
+        let unreachable = iroh::SecretKey::from_bytes(&[9; 32]).public();
+        let dialed = tokio::time::timeout(
+            std::time::Duration::from_secs(10),
+            pool.get(&client, EndpointAddr::new(unreachable)),
+        )
+        .await
+        .unwrap();
+        assert!(dialed.is_err());
+        assert!(pool.is_empty());
+    }
+
+    #[tokio::test]
+    async fn test_local_discovery_without_relays() {
+        let lan = common::config::IrohCommConfig {
+            local_discovery: true,
+            disable_relays: true,
+            ..Default::default()
+        };
+        let backend = echo_backend().await;
+        let routes = RoutingTable::from_services(&[ServiceRecord {
+            service_key: "echo".to_string(),
+            app_layer_protocol: "tcp".to_string(),
+            service_image_manifest_ref: "local-tcp/echo".to_string(),
+            backend_target: Some(backend.to_string()),
+            access_policy: Default::default(),
+            enabled: true,
+            identity_headers: false,
+        }]);
+
+        let server = common::iroh_utils::bind_endpoint(&lan, None).await.unwrap();
+        let server_id = server.id();
+        let proxy = ServiceProxy {
+            dispatcher: StreamDispatcher::new(vec![], routes, server_id),
+            node_id: server_id,
+            tracker: ConnectionTracker::new(Some(server.clone())),
+        };
+        let router = Router::builder(server)
+            .accept(SYNEROYM_ALPN, proxy.clone())
+            .spawn();
+
+        // Dial by id only, the addresses have to come from mDNS
+        let client = common::iroh_utils::bind_endpoint(&lan, None).await.unwrap();
+        let connector = ServiceConnector::new(client, LocalStreams { proxy });
+        let mut stream = tokio::time::timeout(
+            std::time::Duration::from_secs(20),
+            connector.open_stream(EndpointAddr::new(server_id)),
+        )
+        .await
+        .expect("peer not discovered on the local network")
+        .unwrap();
+        handshake::request_service(&mut stream, &StreamHandshake::new("echo", "tcp"))
+            .await
+            .unwrap();
+        stream.write_all(b"x").await.unwrap();
+        assert_eq!(stream.read_u8().await.unwrap(), b'x');
+
+        router.shutdown().await.unwrap();
+    }
 }
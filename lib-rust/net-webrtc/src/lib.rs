@@ -1,28 +1,44 @@
 use anyhow::Result;
 use common::config::Config;
-use futures::{SinkExt, StreamExt};
+use iroh::EndpointId;
 use protocol_base::ProtocolHandler;
+use protocol_base::dispatch::StreamDispatcher;
+use protocol_base::routing::{Caller, RoutingTable};
+use protocol_base::tracker::{ConnectionHandle, ConnectionTracker};
 use std::sync::Arc;
-use tokio::io::AsyncReadExt;
-use tokio::net::TcpStream;
-use tracing::{debug, error, info, warn};
+use tracing::{debug, error, info};
 use webrtc::api::APIBuilder;
 use webrtc::api::interceptor_registry::register_default_interceptors;
 use webrtc::api::media_engine::MediaEngine;
 use webrtc::api::setting_engine::SettingEngine;
 use webrtc::data_channel::RTCDataChannel;
 use webrtc::ice::mdns::MulticastDnsMode;
+use webrtc::ice_transport::ice_server::RTCIceServer;
 use webrtc::interceptor::registry::Registry;
 use webrtc::peer_connection::configuration::RTCConfiguration;
-use webrtc::peer_connection::peer_connection_state::RTCPeerConnectionState;
-use webrtc::peer_connection::sdp::session_description::RTCSessionDescription;
 
+mod signaling;
 mod stream;
+use signaling::SignalingClient;
+pub use signaling::{SignalingHealth, SignalingState, SignalingStatus};
 use stream::WebRTCStream;
 
-pub async fn init(config: &Config, handlers: Vec<Arc<dyn ProtocolHandler>>) -> Result<()> {
+/// Start the WebRTC side, registering on the signaling server as `node_id`, the id
+/// browsers find in `<service>.<peer>.localhost` host names. Peers and their streams are
+/// listed in `tracker`. Returns the health of the signaling connection, if WebRTC is
+/// configured.
+pub async fn init(
+    config: &Config,
+    node_id: EndpointId,
+    handlers: Vec<Arc<dyn ProtocolHandler>>,
+    routes: RoutingTable,
+    tracker: ConnectionTracker,
+) -> Result<Option<SignalingHealth>> {
     if let Some(webrtc_config) = &config.comm_webrtc {
         info!("Initializing WebRTC communication...");
+        if webrtc_config.host_candidates_only {
+            info!("WebRTC gathers host candidates only");
+        }
 
         let signaling_url = webrtc_config
             .signaling_server_url
@@ -46,11 +62,19 @@ pub async fn init(config: &Config, handlers: Vec<Arc<dyn ProtocolHandler>>) -> R
             .with_setting_engine(s)
             .build();
 
-        let rtc_config = RTCConfiguration {
-            ice_servers: vec![webrtc::ice_transport::ice_server::RTCIceServer {
-                urls: vec!["stun:stun.l.google.com:19302".to_owned()],
+        // Without ICE servers only host candidates are gathered
+        let ice_servers = webrtc_config
+            .effective_ice_servers()
+            .iter()
+            .map(|server| RTCIceServer {
+                urls: server.urls.clone(),
+                username: server.username.clone().unwrap_or_default(),
+                credential: server.credential.clone().unwrap_or_default(),
                 ..Default::default()
-            }],
+            })
+            .collect();
+        let rtc_config = RTCConfiguration {
+            ice_servers,
             ..Default::default()
         };
 
@@ -59,153 +83,23 @@ pub async fn init(config: &Config, handlers: Vec<Arc<dyn ProtocolHandler>>) -> R
         let rtc_config = rtc_config.clone();
         let handlers = handlers.clone();
 
-        let peer_id = "localhost".to_string(); // TODO Temporary default. Future: use the node ID hash.
+        let peer_id = node_id.to_string();
 
-        tokio::spawn(async move {
-            if let Err(e) =
-                connect_signaling(peer_id, signaling_url, api, rtc_config, handlers).await
-            {
-                error!("Signaling client error: {:?}", e);
-            }
-        });
+        let dispatcher = StreamDispatcher::new(handlers, routes, node_id);
+        let health =
+            SignalingClient::spawn(peer_id, signaling_url, api, rtc_config, dispatcher, tracker);
 
         info!("WebRTC stack initialized.");
+        return Ok(Some(health));
     }
-    Ok(())
+    Ok(None)
 }
 
-async fn connect_signaling(
-    peer_id: String,
-    url: String,
-    api: Arc<webrtc::api::API>,
-    config: RTCConfiguration,
-    handlers: Vec<Arc<dyn ProtocolHandler>>,
-) -> Result<()> {
-    info!("Connecting to signaling server at {}", url);
-    let (ws_stream, _) = tokio_tungstenite::connect_async(&url).await?;
-    let (mut write, mut read) = ws_stream.split();
-
-    // Register
-    let register_msg = serde_json::json!({
-        "type": "register",
-        "id": peer_id
-    });
-    write
-        .send(tokio_tungstenite::tungstenite::Message::Text(
-            register_msg.to_string().into(),
-        ))
-        .await?;
-    info!("Registered with signaling server as {}", peer_id);
-
-    while let Some(msg) = read.next().await {
-        let msg = match msg {
-            Ok(m) => m,
-            Err(e) => {
-                error!("WebSocket error: {:?}", e);
-                break;
-            }
-        };
-        debug!("Received message from signalling {:?}", msg);
-
-        if let tokio_tungstenite::tungstenite::Message::Text(text) = msg {
-            let v: serde_json::Value = match serde_json::from_str(&text) {
-                Ok(v) => v,
-                Err(_) => continue,
-            };
-
-            let type_str = match v["type"].as_str() {
-                Some(s) => s,
-                None => continue,
-            };
-
-            match type_str {
-                "offer" => {
-                    debug!("Received Offer from {:?}", v["sender"]);
-                    let sdp = match v["sdp"].as_str() {
-                        Some(s) => s,
-                        None => continue,
-                    };
-
-                    let sender_id = v["sender"].as_str().unwrap_or("unknown").to_string();
-                    let peer_id = peer_id.clone();
-                    let api = api.clone();
-                    let config = config.clone();
-                    let handlers = handlers.clone();
-
-                    // Handle the offer in a separate block to catch errors without breaking the loop
-                    let res = async {
-                        // Create new PeerConnection
-                        let pc = Arc::new(api.new_peer_connection(config.clone()).await?);
-
-                        // Set Data Channel handler
-                        let handlers_clone = handlers.clone();
-                        pc.on_data_channel(Box::new(move |d: Arc<RTCDataChannel>| {
-                            let handlers = handlers_clone.clone();
-                            Box::pin(async move {
-                                handle_data_channel(d, handlers).await;
-                            })
-                        }));
-
-                        let pc_clone = pc.clone();
-                        pc.on_peer_connection_state_change(Box::new(
-                            move |s: RTCPeerConnectionState| {
-                                info!("Peer Connection State has changed: {}", s);
-                                if s == RTCPeerConnectionState::Failed
-                                    || s == RTCPeerConnectionState::Disconnected
-                                {
-                                    info!("Peer Connection is {}, closing...", s);
-                                    let pc = pc_clone.clone();
-                                    Box::pin(async move {
-                                        if let Err(e) = pc.close().await {
-                                            error!("Failed to close PeerConnection: {}", e);
-                                        }
-                                    })
-                                } else {
-                                    Box::pin(async {})
-                                }
-                            },
-                        ));
-
-                        // Set Remote Description
-                        let desc = RTCSessionDescription::offer(sdp.to_string())?;
-                        pc.set_remote_description(desc).await?;
-
-                        // Create Answer
-                        let answer = pc.create_answer(None).await?;
-                        pc.set_local_description(answer.clone()).await?;
-
-                        // Send Answer back
-                        let answer_msg = serde_json::json!({
-                            "type": "answer",
-                            "target": sender_id,
-                            "sender": peer_id,
-                            "sdp": answer.sdp
-                        });
-                        write
-                            .send(tokio_tungstenite::tungstenite::Message::Text(
-                                answer_msg.to_string().into(),
-                            ))
-                            .await?;
-                        info!("Sent Answer to {}", sender_id);
-                        Ok::<(), anyhow::Error>(())
-                    }
-                    .await;
-
-                    if let Err(e) = res {
-                        error!("Failed to handle offer from {}: {:?}", sender_id, e);
-                    }
-                }
-                _ => {
-                    debug!("Unhandled signaling message: {}", type_str);
-                }
-            }
-        }
-    }
-
-    Ok(())
-}
-
-async fn handle_data_channel(d: Arc<RTCDataChannel>, _handlers: Vec<Arc<dyn ProtocolHandler>>) {
+async fn handle_data_channel(
+    d: Arc<RTCDataChannel>,
+    dispatcher: StreamDispatcher,
+    peer: ConnectionHandle,
+) {
     let d_label = d.label().to_owned();
     let d_id = d.id();
     info!("New DataChannel {} {}", d_label, d_id);
@@ -214,67 +108,28 @@ async fn handle_data_channel(d: Arc<RTCDataChannel>, _handlers: Vec<Arc<dyn Prot
     d.on_open(Box::new(move || {
         let d = d2.clone();
         let d_label = d_label.clone();
+        let dispatcher = dispatcher.clone();
+        let peer = peer.clone();
         Box::pin(async move {
             info!("DataChannel '{}' open", d_label);
 
             match d.detach().await {
                 Ok(rtc_detached) => {
                     info!("DataChannel '{}' detached successfully", d_label);
-
-                    let mut rtc_stream = WebRTCStream::new(rtc_detached);
-
-                    // 1. Read Preamble: [1 byte len]
-                    let service_name_len = match rtc_stream.read_u8().await {
-                        Ok(n) => n as usize,
-                        Err(e) => {
-                            error!("Failed to read length: {}", e);
-                            return;
-                        }
-                    };
-
-                    // 2. Read Service Name
-                    let mut name_buf = vec![0u8; service_name_len];
-                    if let Err(e) = rtc_stream.read_exact(&mut name_buf).await {
-                        error!("Failed to read service name: {}", e);
-                        return;
-                    }
-
-                    let service_name = String::from_utf8_lossy(&name_buf);
-                    debug!("Service request for: {}", service_name);
-
-                    let backend_addr = match service_name.as_ref() {
-                        "demo3001" => "127.0.0.1:3001",
-                        "demo3002" => "127.0.0.1:3002",
-                        _ => {
-                            warn!("Unknown service: {}", service_name);
-                            return;
-                        }
-                    };
-
-                    match TcpStream::connect(backend_addr).await {
-                        Ok(mut backend_stream) => {
-                            info!("Connected to backend {}, streaming data...", backend_addr);
-
-                            match tokio::io::copy_bidirectional(
-                                &mut rtc_stream,
-                                &mut backend_stream,
+                    // Served like an iroh stream. Browser peers are not authenticated, so
+                    // only public services are reachable over WebRTC
+                    let rtc_stream = WebRTCStream::new(rtc_detached);
+                    let served = dispatcher
+                        .serve_with(rtc_stream, Caller::anonymous(), |service, counters| {
+                            peer.track_stream(
+                                &service.service_key,
+                                &service.app_layer_protocol,
+                                counters,
                             )
-                            .await
-                            {
-                                Ok((client_to_backend, backend_to_client)) => {
-                                    debug!(
-                                        "Streaming finished for {}: sent {}, received {}",
-                                        d_label, client_to_backend, backend_to_client
-                                    );
-                                }
-                                Err(e) => {
-                                    debug!("Streaming error/end for {}: {}", d_label, e);
-                                }
-                            }
-                        }
-                        Err(e) => {
-                            error!("Failed to connect to backend {}: {}", backend_addr, e);
-                        }
+                        })
+                        .await;
+                    if let Err(e) = served {
+                        debug!("DataChannel '{}' failed: {}", d_label, e);
                     }
                 }
                 Err(e) => {
@@ -0,0 +1,813 @@
+//! Client side of the signaling server: registers the node, answers the offers of
+//! browsers and trickles ICE candidates both ways.
+//!
+//! The websocket is reopened with exponential backoff whenever it drops, and the node
+//! registers again under the same id. The state of the connection is published through
+//! [`SignalingHealth`].
+
+use super::handle_data_channel;
+use anyhow::{Context as _, Result, bail};
+use futures::{SinkExt, StreamExt};
+use protocol_base::dispatch::StreamDispatcher;
+use protocol_base::tracker::{ConnectionGuard, ConnectionTracker};
+use rand::Rng;
+use serde::Serialize;
+use std::collections::HashMap;
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+use tokio::sync::{mpsc, watch};
+use tokio_tungstenite::tungstenite::Message;
+use tracing::{debug, error, info, warn};
+use webrtc::data_channel::RTCDataChannel;
+use webrtc::ice_transport::ice_candidate::{RTCIceCandidate, RTCIceCandidateInit};
+use webrtc::peer_connection::RTCPeerConnection;
+use webrtc::peer_connection::configuration::RTCConfiguration;
+use webrtc::peer_connection::peer_connection_state::RTCPeerConnectionState;
+use webrtc::peer_connection::sdp::session_description::RTCSessionDescription;
+
+/// Delay before the first reconnection attempt, doubled on every failed attempt.
+const INITIAL_BACKOFF: Duration = Duration::from_secs(1);
+/// Upper bound of the reconnection delay.
+const MAX_BACKOFF: Duration = Duration::from_secs(60);
+/// How often the signaling server is pinged.
+const PING_INTERVAL: Duration = Duration::from_secs(20);
+/// How long the server may stay silent, pongs included, before the connection is
+/// considered dead.
+const PONG_TIMEOUT: Duration = Duration::from_secs(45);
+/// How long opening the websocket may take.
+const CONNECT_TIMEOUT: Duration = Duration::from_secs(10);
+/// Messages waiting to be sent to the signaling server. Beyond that they are dropped,
+/// browsers retry with a new offer.
+const OUTGOING_QUEUE: usize = 256;
+
+/// Connection state of the signaling client.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "lowercase")]
+pub enum SignalingState {
+    /// Opening the websocket.
+    Connecting,
+    /// The register message was sent. The server does not confirm it, so this means
+    /// browsers can reach the node unless the server refused the id.
+    Registered,
+    /// Waiting before the next attempt.
+    Disconnected,
+}
+
+/// Snapshot of the signaling client, as reported by [`SignalingHealth::status`].
+#[derive(Debug, Clone, Serialize)]
+pub struct SignalingStatus {
+    pub url: String,
+    pub state: SignalingState,
+    /// Failed connection attempts since the node was last registered
+    pub failed_attempts: u32,
+    /// Times the node registered again after losing the connection
+    pub reconnects: u64,
+    pub last_error: Option<String>,
+    /// Seconds since the state last changed
+    pub state_secs: u64,
+    #[serde(skip)]
+    since: Instant,
+}
+
+impl SignalingStatus {
+    fn new(url: String) -> Self {
+        Self {
+            url,
+            state: SignalingState::Connecting,
+            failed_attempts: 0,
+            reconnects: 0,
+            last_error: None,
+            state_secs: 0,
+            since: Instant::now(),
+        }
+    }
+
+    fn set_state(&mut self, state: SignalingState) {
+        self.state = state;
+        self.since = Instant::now();
+    }
+}
+
+/// Live view of the signaling client. Cheap to clone.
+#[derive(Debug, Clone)]
+pub struct SignalingHealth {
+    status: watch::Receiver<SignalingStatus>,
+}
+
+impl SignalingHealth {
+    pub fn status(&self) -> SignalingStatus {
+        let mut status = self.status.borrow().clone();
+        status.state_secs = status.since.elapsed().as_secs();
+        status
+    }
+
+    /// Whether browsers can currently reach the node.
+    pub fn is_registered(&self) -> bool {
+        self.status.borrow().state == SignalingState::Registered
+    }
+
+    /// Wait for the next state change.
+    pub async fn changed(&mut self) -> Result<SignalingStatus> {
+        self.status.changed().await?;
+        Ok(self.status())
+    }
+}
+
+/// Exponential backoff with jitter, so nodes dropped together by a server restart do
+/// not all come back at once.
+#[derive(Debug, Default)]
+struct Backoff {
+    attempts: u32,
+}
+
+impl Backoff {
+    /// The delay before the next attempt, somewhere in the upper half of the current
+    /// backoff.
+    fn next_delay(&mut self) -> Duration {
+        let backoff = INITIAL_BACKOFF
+            .saturating_mul(2u32.saturating_pow(self.attempts))
+            .min(MAX_BACKOFF);
+        self.attempts = self.attempts.saturating_add(1);
+        rand::rng().random_range(backoff / 2..=backoff)
+    }
+
+    fn reset(&mut self) {
+        self.attempts = 0;
+    }
+}
+
+/// Peer connections negotiated over signaling, by the id of the browser that sent the
+/// offer, so its trickled candidates can be added to the right connection.
+type PeerConnections = Arc<Mutex<HashMap<String, Peer>>>;
+
+struct Peer {
+    pc: Arc<RTCPeerConnection>,
+    /// Lists the peer in the tracker for as long as it is connected.
+    _tracked: ConnectionGuard,
+}
+
+pub(crate) struct SignalingClient {
+    peer_id: String,
+    url: String,
+    api: Arc<webrtc::api::API>,
+    config: RTCConfiguration,
+    dispatcher: StreamDispatcher,
+    tracker: ConnectionTracker,
+    peer_connections: PeerConnections,
+    /// Messages for the signaling server. Outlives the websocket, so candidates gathered
+    /// while reconnecting go out once the node is registered again, unless their peer
+    /// connection closed in the meantime.
+    tx: mpsc::Sender<serde_json::Value>,
+    status: watch::Sender<SignalingStatus>,
+}
+
+impl SignalingClient {
+    /// Start the client in the background, registering as `peer_id`.
+    pub(crate) fn spawn(
+        peer_id: String,
+        url: String,
+        api: Arc<webrtc::api::API>,
+        config: RTCConfiguration,
+        dispatcher: StreamDispatcher,
+        tracker: ConnectionTracker,
+    ) -> SignalingHealth {
+        let (client, rx, health) = Self::new(peer_id, url, api, config, dispatcher, tracker);
+        tokio::spawn(client.run(rx));
+        health
+    }
+
+    fn new(
+        peer_id: String,
+        url: String,
+        api: Arc<webrtc::api::API>,
+        config: RTCConfiguration,
+        dispatcher: StreamDispatcher,
+        tracker: ConnectionTracker,
+    ) -> (Self, mpsc::Receiver<serde_json::Value>, SignalingHealth) {
+        let (status, status_rx) = watch::channel(SignalingStatus::new(url.clone()));
+        let (tx, rx) = mpsc::channel(OUTGOING_QUEUE);
+        let client = Self {
+            peer_id,
+            url,
+            api,
+            config,
+            dispatcher,
+            tracker,
+            peer_connections: Default::default(),
+            tx,
+            status,
+        };
+        (client, rx, SignalingHealth { status: status_rx })
+    }
+
+    async fn run(self, mut rx: mpsc::Receiver<serde_json::Value>) {
+        let mut backoff = Backoff::default();
+        let mut registered_before = false;
+        loop {
+            self.status
+                .send_modify(|s| s.set_state(SignalingState::Connecting));
+            let error = match self
+                .session(&mut rx, &mut backoff, &mut registered_before)
+                .await
+            {
+                Ok(()) => "connection closed by the signaling server".to_string(),
+                Err(e) => e.to_string(),
+            };
+
+            let delay = backoff.next_delay();
+            warn!(
+                "Signaling connection to {} lost: {}, reconnecting in {} ms",
+                self.url,
+                error,
+                delay.as_millis()
+            );
+            self.status.send_modify(|s| {
+                s.set_state(SignalingState::Disconnected);
+                s.failed_attempts = backoff.attempts;
+                s.last_error = Some(error);
+            });
+            tokio::time::sleep(delay).await;
+        }
+    }
+
+    /// One websocket connection, from registration until it drops.
+    async fn session(
+        &self,
+        rx: &mut mpsc::Receiver<serde_json::Value>,
+        backoff: &mut Backoff,
+        registered_before: &mut bool,
+    ) -> Result<()> {
+        info!("Connecting to signaling server at {}", self.url);
+        let (ws_stream, _) =
+            tokio::time::timeout(CONNECT_TIMEOUT, tokio_tungstenite::connect_async(&self.url))
+                .await
+                .context("connection timed out")??;
+        let (mut write, mut read) = ws_stream.split();
+
+        // Register
+        let register_msg = serde_json::json!({
+            "type": "register",
+            "id": self.peer_id
+        });
+        write
+            .send(Message::Text(register_msg.to_string().into()))
+            .await?;
+        info!("Registered with signaling server as {}", self.peer_id);
+        backoff.reset();
+        let reconnected = std::mem::replace(registered_before, true);
+        self.status.send_modify(|s| {
+            s.set_state(SignalingState::Registered);
+            s.failed_attempts = 0;
+            if reconnected {
+                s.reconnects += 1;
+            }
+        });
+
+        let mut ping = tokio::time::interval(PING_INTERVAL);
+        // The first tick completes immediately, the connection is fresh already
+        ping.tick().await;
+        let mut last_seen = Instant::now();
+
+        loop {
+            tokio::select! {
+                msg = read.next() => {
+                    let msg = match msg {
+                        Some(Ok(msg)) => msg,
+                        Some(Err(e)) => return Err(e.into()),
+                        None => return Ok(()),
+                    };
+                    last_seen = Instant::now();
+                    debug!("Received message from signalling {:?}", msg);
+                    match msg {
+                        Message::Text(text) => self.handle_message(&text).await,
+                        Message::Close(_) => return Ok(()),
+                        _ => {}
+                    }
+                }
+                Some(msg) = rx.recv() => {
+                    if self.is_for_closed_peer(&msg) {
+                        debug!("Dropping {} for closed peer connection {}", msg["type"], msg["target"]);
+                        continue;
+                    }
+                    write.send(Message::Text(msg.to_string().into())).await?;
+                }
+                _ = ping.tick() => {
+                    if last_seen.elapsed() > PONG_TIMEOUT {
+                        bail!(
+                            "no answer from the signaling server for {}s",
+                            last_seen.elapsed().as_secs()
+                        );
+                    }
+                    write.send(Message::Ping(Default::default())).await?;
+                }
+            }
+        }
+    }
+
+    /// Whether `msg` is meant for a peer connection that is gone, e.g. candidates queued
+    /// while the websocket was down.
+    fn is_for_closed_peer(&self, msg: &serde_json::Value) -> bool {
+        msg["target"]
+            .as_str()
+            .is_some_and(|target| !self.peer_connections.lock().unwrap().contains_key(target))
+    }
+
+    async fn handle_message(&self, text: &str) {
+        let v: serde_json::Value = match serde_json::from_str(text) {
+            Ok(v) => v,
+            Err(_) => return,
+        };
+
+        let type_str = match v["type"].as_str() {
+            Some(s) => s,
+            None => return,
+        };
+        // Set by the signaling server to the id the sender registered, so a peer cannot
+        // act on another peer's connection by claiming its id
+        let sender_id = v["sender"].as_str().unwrap_or("unknown").to_string();
+
+        match type_str {
+            "offer" => {
+                debug!("Received Offer from {}", sender_id);
+                let sdp = match v["sdp"].as_str() {
+                    Some(s) => s,
+                    None => return,
+                };
+
+                // Handled before reading on, so the remote description is set by the
+                // time the candidates following the offer arrive
+                if let Err(e) = self.handle_offer(sdp, &sender_id).await {
+                    error!("Failed to handle offer from {}: {:?}", sender_id, e);
+                }
+            }
+            "candidate" => {
+                // A null candidate marks the end of the sender's candidates
+                if v["candidate"].is_null() {
+                    debug!("End of candidates from {}", sender_id);
+                    return;
+                }
+                let candidate: RTCIceCandidateInit =
+                    match serde_json::from_value(v["candidate"].clone()) {
+                        Ok(c) => c,
+                        Err(e) => {
+                            warn!("Invalid candidate from {}: {}", sender_id, e);
+                            return;
+                        }
+                    };
+                let pc = self
+                    .peer_connections
+                    .lock()
+                    .unwrap()
+                    .get(&sender_id)
+                    .map(|peer| peer.pc.clone());
+                match pc {
+                    Some(pc) => {
+                        if let Err(e) = pc.add_ice_candidate(candidate).await {
+                            warn!("Failed to add candidate from {}: {}", sender_id, e);
+                        }
+                    }
+                    None => debug!("Candidate from {} without an offer", sender_id),
+                }
+            }
+            _ => {
+                debug!("Unhandled signaling message: {}", type_str);
+            }
+        }
+    }
+
+    async fn handle_offer(&self, sdp: &str, sender_id: &str) -> Result<()> {
+        // Create new PeerConnection
+        let pc = Arc::new(self.api.new_peer_connection(self.config.clone()).await?);
+
+        // Set Data Channel handler
+        let tracked = self.tracker.track_peer(sender_id, "webrtc");
+        let dispatcher = self.dispatcher.clone();
+        let handle = tracked.handle();
+        pc.on_data_channel(Box::new(move |d: Arc<RTCDataChannel>| {
+            let dispatcher = dispatcher.clone();
+            let handle = handle.clone();
+            Box::pin(async move {
+                handle_data_channel(d, dispatcher, handle).await;
+            })
+        }));
+
+        // Trickle local candidates to the browser as they are gathered. Those gathered
+        // before the answer is queued are held back, the browser cannot add them yet
+        let held: HeldCandidates = Arc::new(Mutex::new(Some(Vec::new())));
+        let candidate_tx = self.tx.clone();
+        let candidates = held.clone();
+        let target = sender_id.to_string();
+        let sender = self.peer_id.clone();
+        pc.on_ice_candidate(Box::new(move |c: Option<RTCIceCandidate>| {
+            let candidate = match c.map(|c| c.to_json()) {
+                Some(Ok(init)) => serde_json::to_value(init).unwrap_or_default(),
+                Some(Err(e)) => {
+                    warn!("Failed to serialize local candidate: {}", e);
+                    return Box::pin(async {});
+                }
+                None => serde_json::Value::Null,
+            };
+            let msg = serde_json::json!({
+                "type": "candidate",
+                "target": target,
+                "sender": sender,
+                "candidate": candidate
+            });
+            match candidates.lock().unwrap().as_mut() {
+                Some(held) => held.push(msg),
+                None => {
+                    if candidate_tx.try_send(msg).is_err() {
+                        warn!("Signaling queue full, dropping a candidate for {}", target);
+                    }
+                }
+            }
+            Box::pin(async {})
+        }));
+
+        let pc_weak = Arc::downgrade(&pc);
+        let peer_connections_clone = self.peer_connections.clone();
+        let sender = sender_id.to_string();
+        pc.on_peer_connection_state_change(Box::new(move |s: RTCPeerConnectionState| {
+            info!("Peer Connection State has changed: {}", s);
+            if matches!(
+                s,
+                RTCPeerConnectionState::Failed
+                    | RTCPeerConnectionState::Disconnected
+                    | RTCPeerConnectionState::Closed
+            ) {
+                info!("Peer Connection is {}, closing...", s);
+                let pc = pc_weak.upgrade();
+                let peer_connections = peer_connections_clone.clone();
+                let sender = sender.clone();
+                Box::pin(async move {
+                    let Some(pc) = pc else { return };
+                    remove_peer(&peer_connections, &sender, &pc);
+                    close_peer_connection(&pc).await;
+                })
+            } else {
+                Box::pin(async {})
+            }
+        }));
+
+        // The connection is only kept once the offer is answered
+        let answer = match answer_offer(&pc, sdp).await {
+            Ok(answer) => answer,
+            Err(e) => {
+                close_peer_connection(&pc).await;
+                return Err(e);
+            }
+        };
+        let replaced = self.peer_connections.lock().unwrap().insert(
+            sender_id.to_string(),
+            Peer {
+                pc: pc.clone(),
+                _tracked: tracked,
+            },
+        );
+        // A new offer from the same browser replaces its previous connection
+        if let Some(replaced) = replaced {
+            debug!("Replacing the PeerConnection of {}", sender_id);
+            close_peer_connection(&replaced.pc).await;
+        }
+
+        let answer_msg = serde_json::json!({
+            "type": "answer",
+            "target": sender_id,
+            "sender": self.peer_id,
+            "sdp": answer
+        });
+        // The answer goes first, the candidates held back follow it
+        let queued = {
+            let mut held = held.lock().unwrap();
+            self.tx.try_send(answer_msg).map(|()| {
+                for candidate in held.take().unwrap_or_default() {
+                    if self.tx.try_send(candidate).is_err() {
+                        warn!(
+                            "Signaling queue full, dropping a candidate for {}",
+                            sender_id
+                        );
+                    }
+                }
+            })
+        };
+        if queued.is_err() {
+            remove_peer(&self.peer_connections, sender_id, &pc);
+            close_peer_connection(&pc).await;
+            bail!("signaling queue full, answer dropped");
+        }
+        info!("Sent Answer to {}", sender_id);
+        Ok(())
+    }
+}
+
+/// Local candidates of a peer connection waiting for its answer to be queued, `None`
+/// once it is.
+type HeldCandidates = Arc<Mutex<Option<Vec<serde_json::Value>>>>;
+
+/// Apply the browser's offer and answer it, returning the SDP of the answer. Gathering
+/// starts with the local description, its candidates are trickled.
+async fn answer_offer(pc: &RTCPeerConnection, sdp: &str) -> Result<String> {
+    let offer = RTCSessionDescription::offer(sdp.to_string())?;
+    pc.set_remote_description(offer).await?;
+    let answer = pc.create_answer(None).await?;
+    let answer_sdp = answer.sdp.clone();
+    pc.set_local_description(answer).await?;
+    Ok(answer_sdp)
+}
+
+/// Forget the peer connection of `sender`, unless a newer offer replaced it already.
+fn remove_peer(peer_connections: &PeerConnections, sender: &str, pc: &Arc<RTCPeerConnection>) {
+    let mut peer_connections = peer_connections.lock().unwrap();
+    if peer_connections
+        .get(sender)
+        .is_some_and(|current| Arc::ptr_eq(&current.pc, pc))
+    {
+        peer_connections.remove(sender);
+    }
+}
+
+async fn close_peer_connection(pc: &RTCPeerConnection) {
+    if let Err(e) = pc.close().await {
+        error!("Failed to close PeerConnection: {}", e);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use protocol_base::routing::RoutingTable;
+    use std::sync::atomic::{AtomicUsize, Ordering};
+    use tokio::net::TcpListener;
+    use webrtc::api::APIBuilder;
+    use webrtc::api::setting_engine::SettingEngine;
+    use webrtc::ice::mdns::MulticastDnsMode;
+
+    fn test_api() -> Arc<webrtc::api::API> {
+        let mut s = SettingEngine::default();
+        s.set_ice_multicast_dns_mode(MulticastDnsMode::Disabled);
+        Arc::new(APIBuilder::new().with_setting_engine(s).build())
+    }
+
+    fn test_dispatcher() -> StreamDispatcher {
+        let node_id = iroh::SecretKey::generate(&mut rand::rng()).public();
+        StreamDispatcher::new(Vec::new(), RoutingTable::new(), node_id)
+    }
+
+    #[test]
+    fn test_backoff_grows_up_to_the_max() {
+        let mut backoff = Backoff::default();
+        let first = backoff.next_delay();
+        assert!(first >= INITIAL_BACKOFF / 2 && first <= INITIAL_BACKOFF);
+        for _ in 0..20 {
+            assert!(backoff.next_delay() <= MAX_BACKOFF);
+        }
+        assert!(backoff.next_delay() >= MAX_BACKOFF / 2);
+        backoff.reset();
+        assert!(backoff.next_delay() <= INITIAL_BACKOFF);
+    }
+
+    #[tokio::test]
+    async fn test_reconnects_and_registers_again() {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let url = format!("ws://{}/ws", listener.local_addr().unwrap());
+        let mut health = SignalingClient::spawn(
+            "node".to_string(),
+            url,
+            test_api(),
+            RTCConfiguration::default(),
+            test_dispatcher(),
+            ConnectionTracker::new(None),
+        );
+
+        let mut registrations = Vec::new();
+        for _ in 0..2 {
+            let (socket, _) = listener.accept().await.unwrap();
+            let mut ws = tokio_tungstenite::accept_async(socket).await.unwrap();
+            let Some(Ok(Message::Text(text))) = ws.next().await else {
+                panic!("no registration");
+            };
+            let v: serde_json::Value = serde_json::from_str(&text).unwrap();
+            registrations.push((v["type"].clone(), v["id"].clone()));
+            // Drop the first connection, the client has to come back on its own
+            if registrations.len() == 1 {
+                drop(ws);
+                let status = tokio::time::timeout(Duration::from_secs(5), async {
+                    loop {
+                        let status = health.changed().await.unwrap();
+                        if status.state == SignalingState::Disconnected {
+                            return status;
+                        }
+                    }
+                })
+                .await
+                .unwrap();
+                assert_eq!(status.failed_attempts, 1);
+                assert!(status.last_error.is_some());
+            } else {
+                tokio::time::timeout(Duration::from_secs(5), async {
+                    while health.status().reconnects != 1 || !health.is_registered() {
+                        health.changed().await.unwrap();
+                    }
+                })
+                .await
+                .unwrap();
+                assert_eq!(health.status().failed_attempts, 0);
+            }
+        }
+        assert_eq!(registrations[0], registrations[1]);
+        assert_eq!(registrations[0].1, "node");
+    }
+
+    #[tokio::test]
+    async fn test_drops_messages_for_closed_peer_connections() {
+        let tracker = ConnectionTracker::new(None);
+        let (client, _rx, _health) = SignalingClient::new(
+            "node".to_string(),
+            "ws://127.0.0.1:1/ws".to_string(),
+            test_api(),
+            RTCConfiguration::default(),
+            test_dispatcher(),
+            tracker.clone(),
+        );
+        let pc = test_api()
+            .new_peer_connection(RTCConfiguration::default())
+            .await
+            .unwrap();
+        client.peer_connections.lock().unwrap().insert(
+            "open".to_string(),
+            Peer {
+                pc: Arc::new(pc),
+                _tracked: tracker.track_peer("open", "webrtc"),
+            },
+        );
+
+        let candidate = |target: &str| serde_json::json!({"type": "candidate", "target": target});
+        assert!(!client.is_for_closed_peer(&candidate("open")));
+        assert!(client.is_for_closed_peer(&candidate("closed")));
+    }
+
+    #[tokio::test]
+    async fn test_a_new_offer_replaces_the_peer_connection() {
+        let tracker = ConnectionTracker::new(None);
+        let (client, mut rx, _health) = SignalingClient::new(
+            "node".to_string(),
+            "ws://127.0.0.1:1/ws".to_string(),
+            test_api(),
+            RTCConfiguration::default(),
+            test_dispatcher(),
+            tracker.clone(),
+        );
+        let browser = test_api()
+            .new_peer_connection(RTCConfiguration::default())
+            .await
+            .unwrap();
+        browser.create_data_channel("_init", None).await.unwrap();
+        let offer = browser.create_offer(None).await.unwrap().sdp;
+        let current = || {
+            client
+                .peer_connections
+                .lock()
+                .unwrap()
+                .get("browser")
+                .map(|peer| peer.pc.clone())
+        };
+
+        // A broken offer leaves nothing behind
+        assert!(client.handle_offer("garbage", "browser").await.is_err());
+        assert!(current().is_none());
+        assert!(tracker.connections().is_empty());
+
+        client.handle_offer(&offer, "browser").await.unwrap();
+        let first = current().unwrap();
+        assert_eq!(rx.recv().await.unwrap()["type"], "answer");
+
+        client.handle_offer(&offer, "browser").await.unwrap();
+        let second = current().unwrap();
+        assert!(!Arc::ptr_eq(&first, &second));
+        assert_eq!(first.connection_state(), RTCPeerConnectionState::Closed);
+        assert_eq!(tracker.connections().len(), 1);
+        second.close().await.unwrap();
+    }
+
+    #[tokio::test]
+    async fn test_trickle_ice_both_ways() {
+        let port = std::net::TcpListener::bind("127.0.0.1:0")
+            .unwrap()
+            .local_addr()
+            .unwrap()
+            .port();
+        tokio::spawn(signaling_server::start_server(port));
+        let url = format!("ws://127.0.0.1:{}/ws", port);
+        // Wait for the signaling server, then for the node to register on it
+        let mut connected = None;
+        for _ in 0..50 {
+            if let Ok((ws, _)) = tokio_tungstenite::connect_async(&url).await {
+                connected = Some(ws);
+                break;
+            }
+            tokio::time::sleep(Duration::from_millis(100)).await;
+        }
+        let (mut write, mut read) = connected.unwrap().split();
+        let tracker = ConnectionTracker::new(None);
+        let mut health = SignalingClient::spawn(
+            "node".to_string(),
+            url,
+            test_api(),
+            RTCConfiguration::default(),
+            test_dispatcher(),
+            tracker.clone(),
+        );
+        while !health.is_registered() {
+            health.changed().await.unwrap();
+        }
+        // Registered once the message is sent, give the server time to process it
+        tokio::time::sleep(Duration::from_millis(200)).await;
+
+        // Play the browser: send the offer before gathering, then trickle. Messages carry
+        // no sender, the server fills in the registered id
+        let register = serde_json::json!({"type": "register", "id": "browser"});
+        write
+            .send(Message::Text(register.to_string().into()))
+            .await
+            .unwrap();
+        let pc = Arc::new(
+            test_api()
+                .new_peer_connection(RTCConfiguration::default())
+                .await
+                .unwrap(),
+        );
+        pc.create_data_channel("_init", None).await.unwrap();
+        let (state_tx, mut state_rx) = watch::channel(RTCPeerConnectionState::New);
+        pc.on_peer_connection_state_change(Box::new(move |s| {
+            let _ = state_tx.send(s);
+            Box::pin(async {})
+        }));
+        let (tx, mut rx) = mpsc::unbounded_channel::<serde_json::Value>();
+        let candidate_tx = tx.clone();
+        pc.on_ice_candidate(Box::new(move |c: Option<RTCIceCandidate>| {
+            if let Some(c) = c {
+                let _ = candidate_tx.send(serde_json::json!({
+                    "type": "candidate",
+                    "target": "node",
+                    "candidate": c.to_json().unwrap()
+                }));
+            }
+            Box::pin(async {})
+        }));
+        let offer = pc.create_offer(None).await.unwrap();
+        assert!(!offer.sdp.contains("a=candidate"));
+        tx.send(serde_json::json!({
+            "type": "offer",
+            "target": "node",
+            "sdp": offer.sdp
+        }))
+        .unwrap();
+        pc.set_local_description(offer).await.unwrap();
+        tokio::spawn(async move {
+            while let Some(msg) = rx.recv().await {
+                write
+                    .send(Message::Text(msg.to_string().into()))
+                    .await
+                    .unwrap();
+            }
+        });
+
+        let remote_pc = pc.clone();
+        let remote_candidates = Arc::new(AtomicUsize::new(0));
+        let counter = remote_candidates.clone();
+        let reader = tokio::spawn(async move {
+            while let Some(Ok(Message::Text(text))) = read.next().await {
+                let v: serde_json::Value = serde_json::from_str(&text).unwrap();
+                match v["type"].as_str() {
+                    Some("answer") => {
+                        let answer = v["sdp"].as_str().unwrap().to_string();
+                        let desc = RTCSessionDescription::answer(answer).unwrap();
+                        remote_pc.set_remote_description(desc).await.unwrap();
+                    }
+                    Some("candidate") if !v["candidate"].is_null() => {
+                        let init = serde_json::from_value(v["candidate"].clone()).unwrap();
+                        remote_pc.add_ice_candidate(init).await.unwrap();
+                        counter.fetch_add(1, Ordering::Relaxed);
+                    }
+                    _ => {}
+                }
+            }
+        });
+
+        tokio::time::timeout(
+            Duration::from_secs(10),
+            state_rx.wait_for(|s| *s == RTCPeerConnectionState::Connected),
+        )
+        .await
+        .expect("no connection over trickled candidates")
+        .unwrap();
+        reader.abort();
+        assert!(remote_candidates.load(Ordering::Relaxed) > 0);
+        // The browser is listed by the id it registered with
+        let connections = tracker.connections();
+        assert_eq!(connections.len(), 1);
+        assert_eq!(connections[0].remote_id, "browser");
+        assert_eq!(connections[0].path, "webrtc");
+        pc.close().await.unwrap();
+    }
+}
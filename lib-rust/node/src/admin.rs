@@ -0,0 +1,52 @@
+//! Local admin API, for the desktop app and `syneroym-cli connections`, and the
+//! Prometheus metrics at `/metrics`.
+//!
+//! Only bound on 127.0.0.1, anyone able to reach it sees who is connected to the node.
+
+use anyhow::Result;
+use axum::{Json, Router, extract::State, http::header, response::IntoResponse, routing::get};
+use net_webrtc::{SignalingHealth, SignalingStatus};
+use protocol_base::tracker::{ConnectionInfo, ConnectionTracker};
+use std::net::SocketAddr;
+use tokio::net::TcpListener;
+use tracing::info;
+
+#[derive(Clone)]
+struct AdminState {
+    tracker: ConnectionTracker,
+    signaling: Option<SignalingHealth>,
+}
+
+pub async fn start(
+    port: u16,
+    tracker: ConnectionTracker,
+    signaling: Option<SignalingHealth>,
+) -> Result<()> {
+    let app = Router::new()
+        .route("/connections", get(connections))
+        .route("/signaling", get(signaling_status))
+        .route("/metrics", get(metrics))
+        .with_state(AdminState { tracker, signaling });
+
+    let addr = SocketAddr::from(([127, 0, 0, 1], port));
+    let listener = TcpListener::bind(addr).await?;
+    info!("Admin API listening on http://{}", addr);
+    axum::serve(listener, app).await?;
+    Ok(())
+}
+
+async fn metrics() -> impl IntoResponse {
+    (
+        [(header::CONTENT_TYPE, "text/plain; version=0.0.4")],
+        common::metrics::render(),
+    )
+}
+
+async fn connections(State(state): State<AdminState>) -> Json<Vec<ConnectionInfo>> {
+    Json(state.tracker.connections())
+}
+
+/// `null` when WebRTC is not enabled.
+async fn signaling_status(State(state): State<AdminState>) -> Json<Option<SignalingStatus>> {
+    Json(state.signaling.map(|health| health.status()))
+}
@@ -0,0 +1,380 @@
+//! Connectivity diagnostics, as printed by `syneroym-cli doctor`.
+//!
+//! The checks run from a separate, transient endpoint, so they can be run next to a
+//! running node without taking over its identity on the relay. Its bound sockets, relay
+//! and peer path are reported as such, the running node's may differ.
+
+use crate::{LocalNode, ONLINE_TIMEOUT};
+use anyhow::Result;
+use common::config::IrohCommConfig;
+use iroh::endpoint::ConnectionType;
+use iroh::{EndpointAddr, Watcher};
+use protocol_base::SYNEROYM_ALPN;
+use serde::Serialize;
+use std::fmt;
+use std::net::SocketAddr;
+use std::time::{Duration, Instant};
+use tokio::net::TcpStream;
+
+/// How long a TCP connection attempt may take.
+const TCP_TIMEOUT: Duration = Duration::from_secs(5);
+/// How long connecting to the peer may take, discovery included.
+const PEER_TIMEOUT: Duration = Duration::from_secs(15);
+/// How long to wait for a relayed connection to switch to a direct path.
+const HOLEPUNCH_TIMEOUT: Duration = Duration::from_secs(3);
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Status {
+    Ok,
+    Failed,
+    Skipped,
+}
+
+impl fmt::Display for Status {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Status::Ok => write!(f, "ok"),
+            Status::Failed => write!(f, "FAILED"),
+            Status::Skipped => write!(f, "skipped"),
+        }
+    }
+}
+
+#[derive(Debug, Clone, Serialize)]
+pub struct Check {
+    /// What was checked, e.g. an address or URL
+    pub target: Option<String>,
+    pub status: Status,
+    pub detail: String,
+}
+
+impl Check {
+    fn new(target: Option<String>, status: Status, detail: impl Into<String>) -> Self {
+        Self {
+            target,
+            status,
+            detail: detail.into(),
+        }
+    }
+}
+
+#[derive(Debug, Clone, Serialize)]
+pub struct PeerCheck {
+    pub endpoint_id: String,
+    pub status: Status,
+    /// `direct(..)`, `relay(..)` or `mixed(..)`, once connected
+    pub connection: Option<String>,
+    pub latency_ms: Option<f64>,
+    pub detail: String,
+}
+
+#[derive(Debug, Clone, Serialize)]
+pub struct ServiceCheck {
+    pub service: String,
+    pub protocol: String,
+    #[serde(flatten)]
+    pub backend: Check,
+}
+
+#[derive(Debug, Clone, Serialize)]
+pub struct DoctorReport {
+    /// The node identity, if a secret key is configured and already generated
+    pub endpoint_id: Option<String>,
+    /// Sockets bound by the diagnostics endpoint, bound the same way the node binds its own
+    pub bound_addrs: Vec<SocketAddr>,
+    /// Relay reached by the diagnostics endpoint
+    pub relay: Check,
+    /// Connection from the diagnostics endpoint to the peer
+    pub peer: Option<PeerCheck>,
+    pub signaling: Check,
+    pub services: Vec<ServiceCheck>,
+}
+
+impl DoctorReport {
+    /// Whether no check failed.
+    pub fn is_healthy(&self) -> bool {
+        self.relay.status != Status::Failed
+            && self.signaling.status != Status::Failed
+            && self
+                .peer
+                .as_ref()
+                .is_none_or(|p| p.status != Status::Failed)
+            && self
+                .services
+                .iter()
+                .all(|s| s.backend.status != Status::Failed)
+    }
+}
+
+impl fmt::Display for DoctorReport {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match &self.endpoint_id {
+            Some(id) => writeln!(f, "Endpoint ID:  {}", id)?,
+            None => writeln!(
+                f,
+                "Endpoint ID:  none, a transient identity is generated on every start"
+            )?,
+        }
+        // Not the running node's endpoint, its sockets and relay may differ
+        writeln!(f, "Diagnostic endpoint (transient):")?;
+        let bound: Vec<String> = self.bound_addrs.iter().map(|a| a.to_string()).collect();
+        writeln!(f, "  Bound:      {}", bound.join(", "))?;
+        write_check(f, "  Relay:", &self.relay)?;
+        if let Some(peer) = &self.peer {
+            writeln!(f, "  Peer:       {} {}", peer.status, peer.endpoint_id)?;
+            if let Some(connection) = &peer.connection {
+                writeln!(f, "    path:     {}", connection)?;
+            }
+            if let Some(latency) = peer.latency_ms {
+                writeln!(f, "    latency:  {:.1} ms", latency)?;
+            }
+            writeln!(f, "    {}", peer.detail)?;
+        }
+        write_check(f, "Signaling:", &self.signaling)?;
+        writeln!(f, "Services:")?;
+        for service in &self.services {
+            write_check(
+                f,
+                &format!("  {} ({})", service.service, service.protocol),
+                &service.backend,
+            )?;
+        }
+        Ok(())
+    }
+}
+
+fn write_check(f: &mut fmt::Formatter<'_>, label: &str, check: &Check) -> fmt::Result {
+    write!(f, "{:<13} {}", label, check.status)?;
+    if let Some(target) = &check.target {
+        write!(f, " {}", target)?;
+    }
+    writeln!(f, ": {}", check.detail)
+}
+
+impl LocalNode {
+    /// Check the node's connectivity, and its connection to `peer` if given.
+    pub async fn diagnose(&self, peer: Option<EndpointAddr>) -> Result<DoctorReport> {
+        let default_conf = IrohCommConfig::default();
+        let iroh_conf = self.config.comm_iroh.as_ref().unwrap_or(&default_conf);
+        let endpoint_id = match &iroh_conf.secret_key_path {
+            Some(path) if path.exists() => Some(
+                common::iroh_utils::load_or_create_secret_key(path)?
+                    .public()
+                    .to_string(),
+            ),
+            _ => None,
+        };
+
+        let endpoint = common::iroh_utils::bind_endpoint(iroh_conf, None).await?;
+        let bound_addrs = endpoint.bound_sockets();
+
+        let relay = if iroh_conf.disable_relays {
+            Check::new(None, Status::Skipped, "relays are disabled")
+        } else {
+            let started = Instant::now();
+            match tokio::time::timeout(ONLINE_TIMEOUT, endpoint.online()).await {
+                Ok(()) => Check::new(
+                    endpoint.addr().relay_urls().next().map(|u| u.to_string()),
+                    Status::Ok,
+                    format!("connected in {} ms", started.elapsed().as_millis()),
+                ),
+                Err(_) => Check::new(
+                    iroh_conf.relay_url.clone(),
+                    Status::Failed,
+                    format!("no relay reachable within {}s", ONLINE_TIMEOUT.as_secs()),
+                ),
+            }
+        };
+
+        let peer = match peer {
+            Some(addr) => Some(check_peer(&endpoint, addr).await),
+            None => None,
+        };
+        endpoint.close().await;
+
+        let signaling_url = self.get_signaling_server_url();
+        let signaling = match ws_host_port(&signaling_url) {
+            Some(addr) => {
+                let mut check = check_tcp(&addr).await;
+                check.target = Some(signaling_url);
+                check
+            }
+            None => Check::new(
+                Some(signaling_url),
+                Status::Failed,
+                "not a ws:// or wss:// URL",
+            ),
+        };
+
+        let mut services = Vec::new();
+        for service in self.fetch_services().await? {
+            let backend = if !service.enabled {
+                Check::new(
+                    service.backend_target,
+                    Status::Skipped,
+                    "service is disabled",
+                )
+            } else if let Some(addr) = &service.backend_target {
+                check_tcp(addr).await
+            } else {
+                Check::new(None, Status::Skipped, "no backend address")
+            };
+            services.push(ServiceCheck {
+                service: service.service_key,
+                protocol: service.app_layer_protocol,
+                backend,
+            });
+        }
+
+        Ok(DoctorReport {
+            endpoint_id,
+            bound_addrs,
+            relay,
+            peer,
+            signaling,
+            services,
+        })
+    }
+}
+
+async fn check_peer(endpoint: &iroh::Endpoint, addr: EndpointAddr) -> PeerCheck {
+    let id = addr.id;
+    let mut check = PeerCheck {
+        endpoint_id: id.to_string(),
+        status: Status::Failed,
+        connection: None,
+        latency_ms: None,
+        detail: String::new(),
+    };
+
+    let started = Instant::now();
+    let connection =
+        match tokio::time::timeout(PEER_TIMEOUT, endpoint.connect(addr, SYNEROYM_ALPN)).await {
+            Ok(Ok(connection)) => connection,
+            Ok(Err(e)) => {
+                check.detail = format!("connection failed: {:#}", e);
+                return check;
+            }
+            Err(_) => {
+                check.detail = format!("no connection within {}s", PEER_TIMEOUT.as_secs());
+                return check;
+            }
+        };
+    check.status = Status::Ok;
+    check.detail = format!("connected in {} ms", started.elapsed().as_millis());
+
+    // Connections usually start relayed and switch once holepunching succeeds
+    let deadline = Instant::now() + HOLEPUNCH_TIMEOUT;
+    let mut conn_type = ConnectionType::None;
+    while let Some(mut watcher) = endpoint.conn_type(id) {
+        conn_type = watcher.get();
+        if matches!(conn_type, ConnectionType::Direct(_)) || Instant::now() >= deadline {
+            break;
+        }
+        tokio::time::sleep(Duration::from_millis(200)).await;
+    }
+    check.connection = Some(conn_type.to_string());
+    let latency = endpoint.latency(id).unwrap_or_else(|| connection.rtt());
+    check.latency_ms = Some(latency.as_secs_f64() * 1000.0);
+
+    connection.close(0u32.into(), b"diagnostics done");
+    check
+}
+
+async fn check_tcp(addr: &str) -> Check {
+    let started = Instant::now();
+    let (status, detail) = match tokio::time::timeout(TCP_TIMEOUT, TcpStream::connect(addr)).await {
+        Ok(Ok(_)) => (
+            Status::Ok,
+            format!("accepted in {} ms", started.elapsed().as_millis()),
+        ),
+        Ok(Err(e)) => (Status::Failed, e.to_string()),
+        Err(_) => (
+            Status::Failed,
+            format!("no answer within {}s", TCP_TIMEOUT.as_secs()),
+        ),
+    };
+    Check::new(Some(addr.to_string()), status, detail)
+}
+
+/// The `host:port` a WebSocket URL connects to.
+fn ws_host_port(url: &str) -> Option<String> {
+    let (rest, default_port) = if let Some(rest) = url.strip_prefix("ws://") {
+        (rest, 80)
+    } else {
+        (url.strip_prefix("wss://")?, 443)
+    };
+    let authority = rest.split(['/', '?']).next().unwrap_or_default();
+    if authority.is_empty() {
+        return None;
+    }
+    // A bracketed IPv6 address carries colons of its own
+    let has_port = match authority.rfind(']') {
+        Some(end) => authority[end..].contains(':'),
+        None => authority.contains(':'),
+    };
+    if has_port {
+        Some(authority.to_string())
+    } else {
+        Some(format!("{}:{}", authority, default_port))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use common::config::Config;
+    use tokio::net::TcpListener;
+
+    #[test]
+    fn test_ws_host_port() {
+        assert_eq!(
+            ws_host_port("ws://localhost:8000/ws").as_deref(),
+            Some("localhost:8000")
+        );
+        assert_eq!(
+            ws_host_port("wss://signal.example.com/ws").as_deref(),
+            Some("signal.example.com:443")
+        );
+        assert_eq!(ws_host_port("ws://[::1]/ws").as_deref(), Some("[::1]:80"));
+        assert_eq!(ws_host_port("http://localhost/ws"), None);
+    }
+
+    #[tokio::test]
+    async fn test_diagnose_checks_backends() {
+        let backend = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let backend_addr = backend.local_addr().unwrap();
+        let config = Config {
+            comm_iroh: Some(IrohCommConfig {
+                disable_relays: true,
+                ..Default::default()
+            }),
+            data_store_path: ":memory:".into(),
+            ..Default::default()
+        };
+        let node = LocalNode::new(config).await.unwrap();
+        node.store
+            .upsert_service(&store_interface::ServiceRecord {
+                service_key: "up".to_string(),
+                app_layer_protocol: "tcp".to_string(),
+                service_image_manifest_ref: "local-tcp/up".to_string(),
+                backend_target: Some(backend_addr.to_string()),
+                access_policy: Default::default(),
+                enabled: true,
+                identity_headers: false,
+            })
+            .await
+            .unwrap();
+
+        let report = node.diagnose(None).await.unwrap();
+        assert_eq!(report.relay.status, Status::Skipped);
+        assert!(!report.bound_addrs.is_empty());
+        let up = report.services.iter().find(|s| s.service == "up").unwrap();
+        assert_eq!(up.backend.status, Status::Ok);
+
+        let json = serde_json::to_value(&report).unwrap();
+        assert_eq!(json["relay"]["status"], "skipped");
+        assert!(report.to_string().contains("up (tcp)"));
+    }
+}
@@ -1,16 +1,35 @@
-use anyhow::Result;
+use anyhow::{Context, Result};
 use app_host::ServiceRpc;
-use common::config::Config;
+use common::config::{Config, WebRtcCommConfig};
+use iroh::{EndpointId, SecretKey};
+use net_webrtc::{SignalingHealth, SignalingStatus};
 use protocol_base::ProtocolHandler;
-use std::collections::HashMap;
+use protocol_base::routing::RoutingTable;
+use protocol_base::tracker::{ConnectionInfo, ConnectionTracker};
+use registry::{Registry, ServiceAnnouncement};
+use std::collections::{BTreeMap, HashMap};
 
-use std::sync::Arc;
+use std::sync::{Arc, OnceLock};
+use std::time::Duration;
 use store_interface::{ServiceRecord, ServiceStore};
-use tracing::{error, info};
+use tracing::{error, info, warn};
+
+pub mod admin;
+pub mod doctor;
+
+/// How long to wait for a relay connection before starting the proxies anyway.
+const ONLINE_TIMEOUT: Duration = Duration::from_secs(10);
+/// How often the routes are reloaded from the store.
+const ROUTE_REFRESH_INTERVAL: Duration = Duration::from_secs(5);
 
 pub struct LocalNode {
     config: Config,
     store: Arc<dyn ServiceStore>,
+    registry: Registry,
+    /// Set once networking is up, lists the peers of every transport.
+    tracker: OnceLock<ConnectionTracker>,
+    /// Set once the WebRTC side is up.
+    signaling: OnceLock<SignalingHealth>,
 }
 
 impl LocalNode {
@@ -19,7 +38,29 @@ impl LocalNode {
         let store = Arc::new(store_sqlite::SqliteStore::new(
             config.data_store_path.clone(),
         )?);
-        Ok(Self { config, store })
+        // Only the in-memory registry backend exists so far, so publishing is local to
+        // this process until a shared backend is added
+        let registry = Registry::new(Arc::new(registry::memory::MemoryBackend::new()));
+        Ok(Self {
+            config,
+            store,
+            registry,
+            tracker: OnceLock::new(),
+            signaling: OnceLock::new(),
+        })
+    }
+
+    /// The peer connections currently served by this node, empty until it is bootstrapped.
+    pub fn connections(&self) -> Vec<ConnectionInfo> {
+        self.tracker
+            .get()
+            .map(|tracker| tracker.connections())
+            .unwrap_or_default()
+    }
+
+    /// The connection to the signaling server, if WebRTC is enabled and started.
+    pub fn signaling(&self) -> Option<SignalingStatus> {
+        self.signaling.get().map(|health| health.status())
     }
 
     pub async fn bootstrap(&self) -> Result<()> {
@@ -36,7 +77,25 @@ impl LocalNode {
             });
         }
 
+        // Start the embedded relay and wait for it to listen, before networking so the
+        // node can use it as its own
+        if let Some(relay_conf) = &self.config.relay_server
+            && relay_conf.enabled
+        {
+            let addr = relay_conf.bind_addr();
+            info!("Starting Relay Server on {}", addr);
+            let relay = relay_server::start_server(addr)
+                .await
+                .context("starting the relay server")?;
+            tokio::spawn(async move {
+                if let Err(e) = relay.wait().await {
+                    error!("Relay Server failed: {}", e);
+                }
+            });
+        }
+
         // 1. Read Services Configuration
+        info!("Reading services from data store...");
         let services = self.fetch_services().await?;
 
         // 2. Initialize Service RPC
@@ -45,108 +104,322 @@ impl LocalNode {
         // 3. Initialize Protocol Handlers
         let handlers = self.init_protocol_handlers(&services, service_rpcs).await?;
 
-        // 4. Initialize Networking
-        let router_opt = self.init_networking(handlers).await?;
+        // 4. Build the service routing table shared by all transports
+        let routes = RoutingTable::from_services(&services);
 
-        if let Some(router) = router_opt {
-            let endpoint = router.endpoint();
-            // wait for the endpoint to be online
-            router.endpoint().online().await;
+        // 5. Initialize Networking
+        let iroh_opt = self.init_networking(handlers, routes.clone()).await?;
 
-            let node_addr = endpoint.addr();
+        let endpoint = iroh_opt.as_ref().map(|iroh| iroh.router.endpoint().clone());
 
-            let signaling_server_url = self.get_signaling_server_url();
-            let iroh_relay_url = self
-                .config
-                .comm_iroh
-                .as_ref()
-                .and_then(|c| c.relay_url.clone());
+        // Routes and the admin API follow the store and the peers whichever transports
+        // are up
+        let refresh_fut = self.refresh_routes(endpoint.as_ref(), &routes, services.clone());
+        let admin_conf = self.config.admin_api.clone();
+        let tracker = self.tracker.get().cloned();
+        let signaling = self.signaling.get().cloned();
+        let admin_fut = async move {
+            if let Some(admin_conf) = admin_conf
+                && admin_conf.enabled
+                && let Some(tracker) = tracker
+                && let Err(e) = admin::start(admin_conf.port, tracker, signaling).await
+            {
+                error!("Admin API failed: {}", e);
+            }
+        };
+        let iroh_fut = async {
+            match iroh_opt {
+                Some(iroh) => self.serve_iroh(iroh, &services).await,
+                None => Ok(()),
+            }
+        };
+        let ((), (), served) = tokio::join!(refresh_fut, admin_fut, iroh_fut);
+        served?;
+
+        info!("LocalNode bootstrapped successfully.");
+        Ok(())
+    }
 
-            let node_addr_proxy = node_addr.clone();
-            let iroh_relay_url_proxy = iroh_relay_url.clone();
-            let proxy_fut = async move {
-                info!("Starting LocalNode Proxy HTTP...");
+    /// Serve the node over iroh: publish its services and run the local proxies and the
+    /// gateway on its endpoint.
+    async fn serve_iroh(&self, iroh: net_iroh::IrohNode, services: &[ServiceRecord]) -> Result<()> {
+        let net_iroh::IrohNode { router, local, .. } = iroh;
+        let endpoint = router.endpoint();
+        // Wait for the endpoint to be online. That means reaching a relay, so without
+        // relays (or without internet) carry on with direct addresses only
+        let relays_disabled = self
+            .config
+            .comm_iroh
+            .as_ref()
+            .is_some_and(|c| c.disable_relays);
+        if !relays_disabled
+            && tokio::time::timeout(ONLINE_TIMEOUT, endpoint.online())
+                .await
+                .is_err()
+        {
+            warn!("No relay reachable, continuing with direct connections only");
+        }
+
+        let node_addr = endpoint.addr();
+        self.publish_services(endpoint, services).await;
+        info!(
+            "Local services are reachable through other peers at <service>.{}.localhost",
+            common::iroh_utils::endpoint_id_label(&endpoint.id())
+        );
+
+        let signaling_server_url = self.get_signaling_server_url();
+
+        // The proxies share the node's endpoint so peers see a single, stable identity,
+        // and share one connection pool so tunnels to the same peer reuse a connection
+        let connector = net_iroh::client::ServiceConnector::new(endpoint.clone(), local)
+            .with_tokens(self.config.capability_tokens.clone());
+
+        let connector_proxy = connector.clone();
+        let node_addr_proxy = node_addr.clone();
+        let proxy_fut = async move {
+            info!("Starting LocalNode Proxy HTTP...");
+            if let Err(e) = peer_proxy_http::start(3000, connector_proxy, node_addr_proxy).await {
+                error!("Proxy HTTP failed: {}", e);
+            }
+        };
+
+        let socks_conf = self.config.socks_proxy.clone();
+        let connector_socks = connector.clone();
+        let node_addr_socks = node_addr.clone();
+        let socks_fut = async move {
+            if let Some(socks_conf) = socks_conf
+                && socks_conf.enabled
+            {
+                info!("Starting SOCKS5 proxy on port {}", socks_conf.port);
                 if let Err(e) =
-                    peer_proxy_http::start(3000, node_addr_proxy, iroh_relay_url_proxy).await
+                    peer_proxy_http::socks::start(socks_conf.port, connector_socks, node_addr_socks)
+                        .await
                 {
-                    error!("Proxy HTTP failed: {}", e);
+                    error!("SOCKS5 proxy failed: {}", e);
                 }
-            };
+            }
+        };
 
-            let gateway_conf = self.config.peer_gateway.clone();
-            let node_addr_gateway = node_addr.clone();
-            let iroh_relay_url_gateway = iroh_relay_url.clone();
-            let gateway_fut = async move {
-                if let Some(gw_conf) = gateway_conf
-                    && gw_conf.enabled
-                {
-                    info!("Starting Peer Web Gateway on port {}", gw_conf.port);
-                    if let Err(e) = peer_web_gateway::start(
-                        gw_conf.port,
-                        node_addr_gateway,
-                        signaling_server_url,
-                        iroh_relay_url_gateway,
-                    )
-                    .await
-                    {
-                        error!("Peer Web Gateway failed: {}", e);
+        let tunnels = self.config.tcp_tunnels.clone();
+        let connector_tunnels = connector.clone();
+        let node_addr_tunnels = node_addr.clone();
+        let tunnels_fut = async move {
+            if !tunnels.is_empty() {
+                info!("Starting {} TCP tunnels...", tunnels.len());
+                peer_proxy_http::tcp::start_tunnels(tunnels, connector_tunnels, node_addr_tunnels)
+                    .await;
+            }
+        };
+
+        // Browsers still need STUN when the node itself has no [comm_webrtc] section
+        let ice_servers = match &self.config.comm_webrtc {
+            Some(webrtc) => webrtc.effective_ice_servers().to_vec(),
+            None => WebRtcCommConfig::default().effective_ice_servers().to_vec(),
+        };
+        let gateway_conf = self.config.peer_gateway.clone();
+        let node_addr_gateway = node_addr.clone();
+        let gateway_fut = async move {
+            if let Some(gw_conf) = gateway_conf
+                && gw_conf.enabled
+            {
+                info!("Starting Peer Web Gateway on port {}", gw_conf.port);
+                let ca = gw_conf.ca_dir.as_deref().and_then(|dir| {
+                    match peer_web_gateway::tls::LocalCa::load_or_create(dir) {
+                        Ok(ca) => Some(Arc::new(ca)),
+                        Err(e) => {
+                            warn!("Gateway TLS disabled, local CA unavailable: {:#}", e);
+                            None
+                        }
                     }
+                });
+                if let Err(e) = peer_web_gateway::start(
+                    gw_conf.port,
+                    connector,
+                    node_addr_gateway,
+                    signaling_server_url,
+                    &ice_servers,
+                    ca,
+                )
+                .await
+                {
+                    error!("Peer Web Gateway failed: {}", e);
                 }
-            };
+            }
+        };
 
-            tokio::join!(proxy_fut, gateway_fut);
+        tokio::join!(proxy_fut, socks_fut, tunnels_fut, gateway_fut);
 
-            // This makes sure the endpoint in the router is closed properly and connections close gracefully
-            router.shutdown().await?;
-        }
-
-        info!("LocalNode bootstrapped successfully.");
+        // This makes sure the endpoint in the router is closed properly and connections close gracefully
+        router.shutdown().await?;
         Ok(())
     }
 
     async fn init_networking(
         &self,
         handlers: Vec<Arc<dyn ProtocolHandler>>,
-    ) -> Result<Option<iroh::protocol::Router>> {
-        let mut iroh_router = None;
+        routes: RoutingTable,
+    ) -> Result<Option<net_iroh::IrohNode>> {
+        // Iroh goes first whatever the order, its EndpointId identifies the node on the
+        // other transports too
+        let mut iroh_node = None;
+        if self.config.enabled_comms.iter().any(|c| c == "iroh") {
+            info!("Initializing Iroh interface...");
+            iroh_node = net_iroh::init(&self.config, handlers.clone(), routes.clone()).await?;
+        }
+        let node_id = match &iroh_node {
+            Some(node) => node.router.endpoint().id(),
+            None => self.node_identity()?,
+        };
+        // Peers of all transports are listed together
+        let tracker = match &iroh_node {
+            Some(node) => node.tracker.clone(),
+            None => ConnectionTracker::new(None),
+        };
+        let _ = self.tracker.set(tracker.clone());
 
         for comm in &self.config.enabled_comms {
             match comm.as_str() {
-                "iroh" => {
-                    info!("Initializing Iroh interface...");
-                    if let Some(router) = net_iroh::init(&self.config, handlers.clone()).await? {
-                        iroh_router = Some(router);
-                    }
-                }
+                "iroh" => {}
                 "webrtc" => {
                     info!("Initializing WebRTC interface...");
-                    net_webrtc::init(&self.config, handlers.clone()).await?;
+                    if let Some(health) = net_webrtc::init(
+                        &self.config,
+                        node_id,
+                        handlers.clone(),
+                        routes.clone(),
+                        tracker.clone(),
+                    )
+                    .await?
+                    {
+                        let _ = self.signaling.set(health);
+                    }
                 }
                 _ => {
                     info!("Unknown or unimplemented communication interface: {}", comm);
                 }
             }
         }
-        Ok(iroh_router)
+        Ok(iroh_node)
+    }
+
+    /// The node's EndpointId when iroh is not running: the one of the configured secret
+    /// key, or a transient one.
+    fn node_identity(&self) -> Result<EndpointId> {
+        let secret_key_path = self
+            .config
+            .comm_iroh
+            .as_ref()
+            .and_then(|c| c.secret_key_path.as_deref());
+        let secret_key = match secret_key_path {
+            Some(path) => common::iroh_utils::load_or_create_secret_key(path)?,
+            None => SecretKey::generate(&mut rand::rng()),
+        };
+        Ok(secret_key.public())
+    }
+
+    /// Publish a registry record for every enabled service hosted by this node.
+    async fn publish_services(&self, endpoint: &iroh::Endpoint, services: &[ServiceRecord]) {
+        for service in services.iter().filter(|s| s.enabled) {
+            let announcement = ServiceAnnouncement {
+                service_key: service.service_key.clone(),
+                node_id: endpoint.id().to_string(),
+                app_protocol: service.app_layer_protocol.clone(),
+                metadata: BTreeMap::from([(
+                    "manifest".to_string(),
+                    service.service_image_manifest_ref.clone(),
+                )]),
+                withdrawn: false,
+            };
+            match self
+                .registry
+                .publish(endpoint.secret_key(), &announcement)
+                .await
+            {
+                Ok(hash) => info!(
+                    "Published service {} as {} in the local registry",
+                    service.service_key, hash
+                ),
+                Err(e) => warn!("Failed to publish service {}: {}", service.service_key, e),
+            }
+        }
+    }
+
+    /// Withdraw the registry records of services this node no longer hosts.
+    async fn withdraw_services(&self, endpoint: &iroh::Endpoint, service_keys: &[String]) {
+        for service_key in service_keys {
+            match self
+                .registry
+                .withdraw(endpoint.secret_key(), service_key)
+                .await
+            {
+                Ok(hash) => info!(
+                    "Withdrew service {} as {} from the local registry",
+                    service_key, hash
+                ),
+                Err(e) => warn!("Failed to withdraw service {}: {}", service_key, e),
+            }
+        }
+    }
+
+    /// Reload the services from the store periodically, so that services added or changed
+    /// while the node runs (e.g. with `syneroym-cli expose`) are routed, and published if
+    /// iroh is up. Services removed or disabled are withdrawn.
+    async fn refresh_routes(
+        &self,
+        endpoint: Option<&iroh::Endpoint>,
+        routes: &RoutingTable,
+        mut known: Vec<ServiceRecord>,
+    ) {
+        let mut interval = tokio::time::interval(ROUTE_REFRESH_INTERVAL);
+        // The first tick completes immediately, the routes are fresh already
+        interval.tick().await;
+        loop {
+            interval.tick().await;
+            let services = match self.fetch_services().await {
+                Ok(services) => services,
+                Err(e) => {
+                    warn!("Failed to reload services: {}", e);
+                    continue;
+                }
+            };
+            routes.replace_all(&services);
+
+            // New services, and services whose record changed in any way
+            let changed: Vec<ServiceRecord> = services
+                .iter()
+                .filter(|s| s.enabled && !known.contains(s))
+                .cloned()
+                .collect();
+            for service in &changed {
+                info!(
+                    "Service {} is now reachable ({} -> {:?})",
+                    service.service_key, service.app_layer_protocol, service.backend_target
+                );
+            }
+            // Services published before that are gone or disabled now
+            let removed: Vec<String> = known
+                .iter()
+                .filter(|k| k.enabled)
+                .filter(|k| {
+                    !services
+                        .iter()
+                        .any(|s| s.enabled && s.service_key == k.service_key)
+                })
+                .map(|k| k.service_key.clone())
+                .collect();
+            for service_key in &removed {
+                info!("Service {} is no longer reachable", service_key);
+            }
+            if let Some(endpoint) = endpoint {
+                self.publish_services(endpoint, &changed).await;
+                self.withdraw_services(endpoint, &removed).await;
+            }
+            known = services;
+        }
     }
 
     async fn fetch_services(&self) -> Result<Vec<ServiceRecord>> {
-        info!("Reading services from data store...",);
-        let mut services = self.store.get_services().await?;
-
-        // Add test services
-        services.push(ServiceRecord {
-            service_key: "test1".to_string(),
-            app_layer_protocol: "http".to_string(),
-            service_image_manifest_ref: "local-http/test1".to_string(),
-        });
-        services.push(ServiceRecord {
-            service_key: "test2".to_string(),
-            app_layer_protocol: "http".to_string(),
-            service_image_manifest_ref: "local-http/test2".to_string(),
-        });
-
-        Ok(services)
+        self.store.get_services().await
     }
 
     async fn init_service_rpc(
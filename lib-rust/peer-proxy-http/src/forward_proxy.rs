@@ -0,0 +1,256 @@
+//! Standard HTTP forward proxy mode, for clients configured with `HTTP_PROXY` or
+//! `HTTPS_PROXY`.
+//!
+//! `CONNECT <service>.<peer>.syneroym:443` opens a tunnel to the service, and requests in
+//! absolute form (`GET http://<service>.<peer>.syneroym/path`) are passed on in origin
+//! form. Absolute form requests are served one per connection, since a client may send
+//! its next request on the same connection to a different host.
+
+use crate::AppState;
+use crate::tcp::TCP_PROTOCOL;
+use anyhow::{Result, anyhow, bail};
+use common::metrics::{self, Side};
+use protocol_base::forward::http_error_response;
+use protocol_base::handshake;
+use tokio::io::{AsyncReadExt, AsyncWriteExt};
+use tokio::net::TcpStream;
+use tracing::debug;
+
+const MAX_HEAD_LEN: usize = 64 * 1024;
+const MAX_HEADERS: usize = 100;
+
+/// Hop-by-hop headers meant for the proxy, not passed on to the service.
+const PROXY_HEADERS: [&str; 4] = [
+    "proxy-connection",
+    "proxy-authorization",
+    "connection",
+    "keep-alive",
+];
+
+#[derive(Debug, PartialEq, Eq)]
+enum ProxyRequest {
+    /// `CONNECT host:port`
+    Connect { authority: String },
+    /// A request in absolute form, with `head` rewritten to origin form.
+    Absolute { host: String, head: Vec<u8> },
+}
+
+/// Whether the (peeked) start of a plain HTTP stream is addressed to a forward proxy.
+pub(crate) fn is_proxy_request(peeked: &[u8]) -> bool {
+    let line = peeked.split(|&b| b == b'\n').next().unwrap_or_default();
+    let mut parts = line.split(|&b| b == b' ');
+    let method = parts.next().unwrap_or_default();
+    let target = parts.next().unwrap_or_default();
+    method.eq_ignore_ascii_case(b"CONNECT")
+        || (target.len() > 7 && target[..7].eq_ignore_ascii_case(b"http://"))
+}
+
+/// Serve a forward proxy request.
+pub(crate) async fn serve(mut client: TcpStream, state: &AppState) -> Result<()> {
+    let (head, rest) = read_head(&mut client).await?;
+    let request = match parse_request(&head) {
+        Ok(request) => request,
+        Err(e) => {
+            let response = http_error_response(400, "Bad Request", &e.to_string());
+            client.write_all(response.as_bytes()).await?;
+            return Err(e);
+        }
+    };
+
+    let (host, app_protocol) = match &request {
+        ProxyRequest::Connect { authority } => (authority.as_str(), TCP_PROTOCOL),
+        ProxyRequest::Absolute { host, .. } => (host.as_str(), "http"),
+    };
+    let (svc_name, target) = match state.resolve_host(host) {
+        Ok(resolved) => resolved,
+        Err(e) => {
+            let response = http_error_response(400, "Bad Request", &e.to_string());
+            client.write_all(response.as_bytes()).await?;
+            return Err(e);
+        }
+    };
+    debug!(
+        "Forward proxy request for {} on {}",
+        svc_name,
+        target.id.fmt_short()
+    );
+
+    let peer_id = target.id;
+    let handshake_request = state
+        .connector
+        .handshake_for(&peer_id, &svc_name, app_protocol);
+    let mut iroh_stream = match state.connector.open_stream(target).await {
+        Ok(stream) => stream,
+        Err(e) => {
+            let response = http_error_response(502, "Bad Gateway", &e.to_string());
+            client.write_all(response.as_bytes()).await?;
+            return Err(e);
+        }
+    };
+    if let Err(e) = handshake::request_service(&mut iroh_stream, &handshake_request).await {
+        client.write_all(e.to_http_response().as_bytes()).await?;
+        return Err(e.into());
+    }
+
+    match &request {
+        ProxyRequest::Connect { .. } => {
+            client
+                .write_all(b"HTTP/1.1 200 Connection Established\r\n\r\n")
+                .await?;
+        }
+        ProxyRequest::Absolute { head, .. } => iroh_stream.write_all(head).await?,
+    }
+    iroh_stream.write_all(&rest).await?;
+
+    let (client_to_backend, backend_to_client) = metrics::copy_metered(
+        &mut client,
+        &mut iroh_stream,
+        Side::Client,
+        &svc_name,
+        &peer_id.to_string(),
+    )
+    .await?;
+    debug!(
+        "forward proxy copied bytes {}&{}",
+        client_to_backend, backend_to_client
+    );
+    Ok(())
+}
+
+/// Read the request head, returning it and any bytes read past its end.
+async fn read_head(client: &mut TcpStream) -> Result<(Vec<u8>, Vec<u8>)> {
+    let mut buf = Vec::new();
+    let mut chunk = [0u8; 4096];
+    loop {
+        let n = client.read(&mut chunk).await?;
+        if n == 0 {
+            bail!("connection closed in the middle of a request head");
+        }
+        // The end of the head may straddle two reads
+        let search_from = buf.len().saturating_sub(3);
+        buf.extend_from_slice(&chunk[..n]);
+        if let Some(pos) = buf[search_from..].windows(4).position(|w| w == b"\r\n\r\n") {
+            let rest = buf.split_off(search_from + pos + 4);
+            return Ok((buf, rest));
+        }
+        if buf.len() > MAX_HEAD_LEN {
+            bail!("request head larger than {} bytes", MAX_HEAD_LEN);
+        }
+    }
+}
+
+fn parse_request(head: &[u8]) -> Result<ProxyRequest> {
+    let mut headers = [httparse::EMPTY_HEADER; MAX_HEADERS];
+    let mut request = httparse::Request::new(&mut headers);
+    match request.parse(head)? {
+        httparse::Status::Complete(_) => {}
+        httparse::Status::Partial => bail!("incomplete request head"),
+    }
+    let method = request.method.unwrap_or_default();
+    let path = request.path.unwrap_or_default();
+
+    if method.eq_ignore_ascii_case("CONNECT") {
+        return Ok(ProxyRequest::Connect {
+            authority: path.to_string(),
+        });
+    }
+
+    let (host, origin) = split_absolute_uri(path)?;
+    let mut rewritten = format!(
+        "{} {} HTTP/1.{}\r\n",
+        method,
+        origin,
+        request.version.unwrap_or(1)
+    )
+    .into_bytes();
+    for header in request.headers.iter() {
+        if PROXY_HEADERS
+            .iter()
+            .any(|h| header.name.eq_ignore_ascii_case(h))
+        {
+            continue;
+        }
+        rewritten.extend_from_slice(header.name.as_bytes());
+        rewritten.extend_from_slice(b": ");
+        rewritten.extend_from_slice(header.value);
+        rewritten.extend_from_slice(b"\r\n");
+    }
+    rewritten.extend_from_slice(b"Connection: close\r\n\r\n");
+
+    Ok(ProxyRequest::Absolute {
+        host: host.to_string(),
+        head: rewritten,
+    })
+}
+
+/// Split `http://host[:port]/path?query` into the host and the origin form target.
+fn split_absolute_uri(uri: &str) -> Result<(&str, String)> {
+    let rest = uri
+        .get(..7)
+        .filter(|scheme| scheme.eq_ignore_ascii_case("http://"))
+        .map(|_| &uri[7..])
+        .ok_or_else(|| anyhow!("not an absolute http URI: {}", uri))?;
+    let end = rest.find(['/', '?']).unwrap_or(rest.len());
+    let authority = &rest[..end];
+    // Drop any userinfo
+    let host = authority.rsplit('@').next().unwrap_or(authority);
+    if host.is_empty() {
+        bail!("missing host in {}", uri);
+    }
+
+    let origin = &rest[end..];
+    let origin = if origin.starts_with('/') {
+        origin.to_string()
+    } else {
+        format!("/{}", origin)
+    };
+    Ok((host, origin))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_detects_proxy_requests() {
+        assert!(is_proxy_request(
+            b"CONNECT db.abc.syneroym:443 HTTP/1.1\r\n"
+        ));
+        assert!(is_proxy_request(
+            b"GET http://demo.abc.syneroym/ HTTP/1.1\r\n"
+        ));
+        assert!(!is_proxy_request(
+            b"GET / HTTP/1.1\r\nHost: demo.localhost\r\n"
+        ));
+    }
+
+    #[test]
+    fn test_rewrites_absolute_form_requests() {
+        let head = b"POST http://user@demo.abc.syneroym:8080/api?q=1 HTTP/1.1\r\n\
+            Host: demo.abc.syneroym:8080\r\n\
+            Proxy-Connection: keep-alive\r\n\
+            Content-Length: 2\r\n\r\n";
+        let ProxyRequest::Absolute { host, head } = parse_request(head).unwrap() else {
+            panic!("expected an absolute form request");
+        };
+        assert_eq!(host, "demo.abc.syneroym:8080");
+        assert_eq!(
+            String::from_utf8(head).unwrap(),
+            "POST /api?q=1 HTTP/1.1\r\n\
+            Host: demo.abc.syneroym:8080\r\n\
+            Content-Length: 2\r\n\
+            Connection: close\r\n\r\n"
+        );
+
+        assert_eq!(
+            split_absolute_uri("http://demo.syneroym?x").unwrap(),
+            ("demo.syneroym", "/?x".to_string())
+        );
+        assert_eq!(
+            parse_request(b"CONNECT db.abc.syneroym:5432 HTTP/1.1\r\n\r\n").unwrap(),
+            ProxyRequest::Connect {
+                authority: "db.abc.syneroym:5432".to_string()
+            }
+        );
+    }
+}
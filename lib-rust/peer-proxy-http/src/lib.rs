@@ -1,40 +1,62 @@
 use anyhow::anyhow;
-use common::iroh_utils::IrohStream;
+use common::metrics::{self, Side};
 use common::protocol_utils::{
-    extract_host_from_http, extract_service_from_host, extract_sni, is_tls_client_hello,
+    extract_host_from_http, extract_peer_from_host, extract_service_from_host, extract_sni,
+    is_tls_client_hello,
 };
-use iroh::{Endpoint, EndpointAddr};
-use protocol_base::SYNEROYM_ALPN;
+use iroh::{EndpointAddr, EndpointId};
+use net_iroh::client::ServiceConnector;
+use protocol_base::handshake;
 use std::net::SocketAddr;
 use std::sync::Arc;
 
-use tokio::io::{self, AsyncWriteExt};
+use tokio::io::AsyncWriteExt;
 use tokio::net::TcpListener;
 use tracing::{debug, info};
 
+mod forward_proxy;
+pub mod socks;
+pub mod tcp;
+
 type NodeId = EndpointAddr;
 
 struct AppState {
-    iroh: Endpoint,
+    connector: ServiceConnector,
+    /// Node to use when the host name does not name a peer.
     target: NodeId,
 }
 
-pub async fn start(
-    port: u16,
-    target: NodeId,
-    iroh_relay_url: Option<String>,
-) -> anyhow::Result<()> {
+impl AppState {
+    /// The service and peer named by a `<service>[.<peer>].<domain>` host.
+    fn resolve_host(&self, hostname: &str) -> anyhow::Result<(String, NodeId)> {
+        let svc_name = extract_service_from_host(hostname)?;
+        let target = match extract_peer_from_host(hostname) {
+            Some(peer) => {
+                let id = peer
+                    .parse::<EndpointId>()
+                    .map_err(|e| anyhow!("invalid peer id {} in host {}: {}", peer, hostname, e))?;
+                EndpointAddr::new(id)
+            }
+            None => self.target.clone(),
+        };
+        Ok((svc_name, target))
+    }
+}
+
+/// Start the local HTTP proxy, dialing peers through the node's own `connector` so that
+/// remote services see this node's identity.
+///
+/// Requests for `<service>.<peer>.localhost` are tunneled to the peer with that
+/// EndpointId, its addresses are resolved through discovery. Requests for
+/// `<service>.localhost` go to `target`. The proxy also works as a standard forward
+/// proxy (`HTTP_PROXY`/`HTTPS_PROXY`) for `<service>.<peer>.syneroym` names.
+pub async fn start(port: u16, connector: ServiceConnector, target: NodeId) -> anyhow::Result<()> {
     info!(
         "Starting LocalNode HTTP Proxy on port {}, target: {:?}",
         port, target
     );
 
-    let endpoint = common::iroh_utils::bind_endpoint(iroh_relay_url).await?;
-
-    let state = Arc::new(AppState {
-        iroh: endpoint,
-        target,
-    });
+    let state = Arc::new(AppState { connector, target });
 
     let pxy_addr = SocketAddr::from(([127, 0, 0, 1], port));
     let listener = TcpListener::bind(pxy_addr).await?;
@@ -66,7 +88,12 @@ async fn proxy_connection(
     }
 
     // Determine if this is TLS or plain HTTP
-    let hostname = if is_tls_client_hello(&peek_buf[..n]) {
+    let is_tls = is_tls_client_hello(&peek_buf[..n]);
+    if !is_tls && forward_proxy::is_proxy_request(&peek_buf[..n]) {
+        debug!("Detected forward proxy request");
+        return forward_proxy::serve(client, &state).await;
+    }
+    let hostname = if is_tls {
         debug!("Detected TLS connection");
         extract_sni(&peek_buf[..n])?
     } else {
@@ -75,25 +102,32 @@ async fn proxy_connection(
     };
 
     debug!("Extracted hostname: {}", hostname);
-    let svc_name = extract_service_from_host(hostname.as_str())?;
+    let (svc_name, target) = state.resolve_host(&hostname)?;
     debug!("Extracted service name: {}", svc_name);
-
-    // 1. Connect to Iroh
-    let connection = state
-        .iroh
-        .connect(state.target.clone(), SYNEROYM_ALPN)
-        .await?;
-    let (send, recv) = connection.open_bi().await?;
-
-    // 2. Handshake (send service name)
-    let svc_raw = svc_name.as_bytes();
-    let mut iroh_stream = IrohStream::new(send, recv);
-    iroh_stream.write_u8(svc_raw.len() as u8).await?;
-    iroh_stream.write_all(svc_raw).await?;
+    debug!("Target peer: {}", target.id.fmt_short());
+
+    // 1. Open a stream over a (pooled) Iroh connection
+    let mut iroh_stream = state.connector.open_stream(target.clone()).await?;
+
+    // 2. Handshake (request the service)
+    let request = state.connector.handshake_for(&target.id, &svc_name, "http");
+    if let Err(e) = handshake::request_service(&mut iroh_stream, &request).await {
+        // Plain HTTP clients get a readable error instead of a dropped connection
+        if !is_tls {
+            client.write_all(e.to_http_response().as_bytes()).await?;
+        }
+        return Err(e.into());
+    }
 
     // Bidirectional streaming - copies all bytes in both directions
-    let (client_to_backend, backend_to_client) =
-        io::copy_bidirectional(&mut client, &mut iroh_stream).await?;
+    let (client_to_backend, backend_to_client) = metrics::copy_metered(
+        &mut client,
+        &mut iroh_stream,
+        Side::Client,
+        &svc_name,
+        &target.id.to_string(),
+    )
+    .await?;
     debug!(
         "proxy copied bytes {}&{}",
         client_to_backend, backend_to_client
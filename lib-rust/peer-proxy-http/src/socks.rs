@@ -0,0 +1,285 @@
+//! SOCKS5 front-end for tools that can use a SOCKS proxy but not `*.localhost` names.
+//!
+//! Only `CONNECT` to domain names is supported. `<service>.<peer>.syneroym` is tunneled
+//! to the service on the peer with that EndpointId, `<service>.syneroym` to the default
+//! target. The port is ignored, the service decides where the stream ends up.
+
+use crate::tcp::{TCP_PROTOCOL, TunnelTarget};
+use anyhow::{Result, anyhow};
+use common::metrics::{self, Side};
+use iroh::{EndpointAddr, EndpointId};
+use net_iroh::client::ServiceConnector;
+use protocol_base::handshake::{self, HandshakeError, HandshakeStatus};
+use std::net::SocketAddr;
+use std::sync::Arc;
+use tokio::io::{self, AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt};
+use tokio::net::{TcpListener, TcpStream};
+use tracing::{debug, info};
+
+/// Top level domain of the names the proxy accepts.
+pub const SYNEROYM_DOMAIN: &str = "syneroym";
+
+const SOCKS_VERSION: u8 = 5;
+const NO_AUTH: u8 = 0x00;
+const NO_ACCEPTABLE_METHOD: u8 = 0xff;
+const CMD_CONNECT: u8 = 0x01;
+const ATYP_IPV4: u8 = 0x01;
+const ATYP_DOMAIN: u8 = 0x03;
+const ATYP_IPV6: u8 = 0x04;
+
+/// Reply codes from RFC 1928 section 6.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[repr(u8)]
+enum Reply {
+    Succeeded = 0x00,
+    GeneralFailure = 0x01,
+    NotAllowed = 0x02,
+    HostUnreachable = 0x04,
+    ConnectionRefused = 0x05,
+    CommandNotSupported = 0x07,
+    AddressTypeNotSupported = 0x08,
+}
+
+impl From<&HandshakeError> for Reply {
+    fn from(e: &HandshakeError) -> Self {
+        match e {
+            HandshakeError::Rejected { status, .. } => match status {
+                HandshakeStatus::UnknownService => Reply::HostUnreachable,
+                HandshakeStatus::Unauthorized => Reply::NotAllowed,
+                HandshakeStatus::BackendDown => Reply::ConnectionRefused,
+                _ => Reply::GeneralFailure,
+            },
+            _ => Reply::GeneralFailure,
+        }
+    }
+}
+
+struct AppState {
+    connector: ServiceConnector,
+    /// Node to use when the name does not include a peer.
+    target: EndpointAddr,
+}
+
+/// Start the SOCKS5 proxy on `127.0.0.1:port`.
+pub async fn start(port: u16, connector: ServiceConnector, target: EndpointAddr) -> Result<()> {
+    let state = Arc::new(AppState { connector, target });
+
+    let addr = SocketAddr::from(([127, 0, 0, 1], port));
+    let listener = TcpListener::bind(addr).await?;
+    info!("SOCKS5 proxy listening on {}", addr);
+
+    loop {
+        let (client, cl_addr) = listener.accept().await?;
+        debug!("New SOCKS connection from: {}", cl_addr);
+        let state = state.clone();
+        tokio::spawn(async move {
+            if let Err(e) = socks_connection(client, state).await {
+                debug!("SOCKS connection error: {e}");
+            }
+        });
+    }
+}
+
+async fn socks_connection(mut client: TcpStream, state: Arc<AppState>) -> Result<()> {
+    let host = match read_connect_request(&mut client).await? {
+        Ok(host) => host,
+        Err(reply) => {
+            write_reply(&mut client, reply).await?;
+            return Err(anyhow!("SOCKS request refused: {:?}", reply));
+        }
+    };
+    let target = match target_from_host(&host) {
+        Ok(target) => target,
+        Err(e) => {
+            write_reply(&mut client, Reply::NotAllowed).await?;
+            return Err(e);
+        }
+    };
+    debug!("SOCKS CONNECT to {} ({})", host, target);
+
+    let peer = match target.peer {
+        Some(id) => EndpointAddr::new(id),
+        None => state.target.clone(),
+    };
+    let peer_id = peer.id;
+    let request = state
+        .connector
+        .handshake_for(&peer_id, &target.service, TCP_PROTOCOL);
+    let mut iroh_stream = match state.connector.open_stream(peer).await {
+        Ok(stream) => stream,
+        Err(e) => {
+            write_reply(&mut client, Reply::HostUnreachable).await?;
+            return Err(e);
+        }
+    };
+    if let Err(e) = handshake::request_service(&mut iroh_stream, &request).await {
+        write_reply(&mut client, Reply::from(&e)).await?;
+        return Err(e.into());
+    }
+    write_reply(&mut client, Reply::Succeeded).await?;
+
+    let (client_to_backend, backend_to_client) = metrics::copy_metered(
+        &mut client,
+        &mut iroh_stream,
+        Side::Client,
+        &target.service,
+        &peer_id.to_string(),
+    )
+    .await?;
+    debug!(
+        "SOCKS proxy copied bytes {}&{}",
+        client_to_backend, backend_to_client
+    );
+    Ok(())
+}
+
+/// Run the method negotiation and read the CONNECT request, returning the requested
+/// domain name, or the reply to refuse the request with.
+async fn read_connect_request<S>(stream: &mut S) -> Result<Result<String, Reply>>
+where
+    S: AsyncRead + AsyncWrite + Unpin,
+{
+    let version = stream.read_u8().await?;
+    if version != SOCKS_VERSION {
+        return Err(anyhow!("unsupported SOCKS version {}", version));
+    }
+    let mut methods = vec![0u8; stream.read_u8().await? as usize];
+    stream.read_exact(&mut methods).await?;
+    if !methods.contains(&NO_AUTH) {
+        stream
+            .write_all(&[SOCKS_VERSION, NO_ACCEPTABLE_METHOD])
+            .await?;
+        return Err(anyhow!("client does not offer unauthenticated access"));
+    }
+    stream.write_all(&[SOCKS_VERSION, NO_AUTH]).await?;
+
+    let mut header = [0u8; 4];
+    stream.read_exact(&mut header).await?;
+    let [version, command, _reserved, address_type] = header;
+    if version != SOCKS_VERSION {
+        return Err(anyhow!("unsupported SOCKS version {}", version));
+    }
+
+    // Consume the address so the request is fully read before replying
+    let host = match address_type {
+        ATYP_DOMAIN => {
+            let mut name = vec![0u8; stream.read_u8().await? as usize];
+            stream.read_exact(&mut name).await?;
+            Some(String::from_utf8(name).map_err(|_| anyhow!("domain name is not UTF-8"))?)
+        }
+        ATYP_IPV4 => {
+            stream.read_exact(&mut [0u8; 4]).await?;
+            None
+        }
+        ATYP_IPV6 => {
+            stream.read_exact(&mut [0u8; 16]).await?;
+            None
+        }
+        other => return Err(anyhow!("unknown SOCKS address type {}", other)),
+    };
+    let _port = stream.read_u16().await?;
+
+    if command != CMD_CONNECT {
+        return Ok(Err(Reply::CommandNotSupported));
+    }
+    Ok(host.ok_or(Reply::AddressTypeNotSupported))
+}
+
+async fn write_reply<S: AsyncWrite + Unpin>(stream: &mut S, reply: Reply) -> io::Result<()> {
+    // No meaningful bound address, the stream ends at a peer
+    let mut message = [0u8; 10];
+    message[0] = SOCKS_VERSION;
+    message[1] = reply as u8;
+    message[3] = ATYP_IPV4;
+    stream.write_all(&message).await
+}
+
+/// Map `<service>.<peer>.syneroym` or `<service>.syneroym` to a tunnel target.
+fn target_from_host(host: &str) -> Result<TunnelTarget> {
+    let host = host.trim_end_matches('.');
+    let name = host
+        .len()
+        .checked_sub(SYNEROYM_DOMAIN.len() + 1)
+        .filter(|&end| {
+            host.as_bytes()[end] == b'.' && host[end + 1..].eq_ignore_ascii_case(SYNEROYM_DOMAIN)
+        })
+        .map(|end| &host[..end])
+        .ok_or_else(|| anyhow!("{} is not a .{} name", host, SYNEROYM_DOMAIN))?;
+
+    let (service, peer) = match name.split_once('.') {
+        Some((service, peer)) => {
+            let id = peer
+                .parse::<EndpointId>()
+                .map_err(|e| anyhow!("invalid peer id {} in host {}: {}", peer, host, e))?;
+            (service, Some(id))
+        }
+        None => (name, None),
+    };
+    if service.is_empty() {
+        return Err(anyhow!("missing service name in host {}", host));
+    }
+    Ok(TunnelTarget {
+        service: service.to_string(),
+        peer,
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use common::iroh_utils::endpoint_id_label;
+    use iroh::SecretKey;
+
+    #[test]
+    fn test_target_from_host() {
+        let peer = SecretKey::generate(&mut rand::rng()).public();
+
+        let host = format!("postgres.{}.syneroym", endpoint_id_label(&peer));
+        let target = target_from_host(&host).unwrap();
+        assert_eq!(target.service, "postgres");
+        assert_eq!(target.peer, Some(peer));
+
+        let local = target_from_host("redis.SYNEROYM").unwrap();
+        assert_eq!(local.service, "redis");
+        assert_eq!(local.peer, None);
+
+        assert!(target_from_host("example.com").is_err());
+        assert!(target_from_host("syneroym").is_err());
+        assert!(target_from_host("ssh.not-a-peer.syneroym").is_err());
+    }
+
+    #[tokio::test]
+    async fn test_connect_request() {
+        let (mut client, mut server) = tokio::io::duplex(1024);
+        let server = tokio::spawn(async move { read_connect_request(&mut server).await });
+
+        let name = b"demo.syneroym";
+        client.write_all(&[5, 2, 0x02, NO_AUTH]).await.unwrap();
+        let mut choice = [0u8; 2];
+        client.read_exact(&mut choice).await.unwrap();
+        assert_eq!(choice, [5, NO_AUTH]);
+
+        let mut request = vec![5, CMD_CONNECT, 0, ATYP_DOMAIN, name.len() as u8];
+        request.extend_from_slice(name);
+        request.extend_from_slice(&5432u16.to_be_bytes());
+        client.write_all(&request).await.unwrap();
+        assert_eq!(
+            server.await.unwrap().unwrap(),
+            Ok("demo.syneroym".to_string())
+        );
+
+        // IP addresses cannot name a service
+        let (mut client, mut server) = tokio::io::duplex(1024);
+        let server = tokio::spawn(async move { read_connect_request(&mut server).await });
+        client.write_all(&[5, 1, NO_AUTH]).await.unwrap();
+        client.read_exact(&mut choice).await.unwrap();
+        client
+            .write_all(&[5, CMD_CONNECT, 0, ATYP_IPV4, 127, 0, 0, 1, 0, 80])
+            .await
+            .unwrap();
+        assert_eq!(
+            server.await.unwrap().unwrap(),
+            Err(Reply::AddressTypeNotSupported)
+        );
+    }
+}
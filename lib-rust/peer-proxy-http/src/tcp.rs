@@ -0,0 +1,182 @@
+//! Raw TCP tunnels, one local port per remote service.
+//!
+//! Protocols like SSH, Postgres or Redis carry no host name to route on, so each
+//! configured service gets its own local listener and every accepted connection is
+//! tunneled to that service byte for byte.
+
+use anyhow::{Result, anyhow};
+use common::config::TcpTunnelConfig;
+use common::metrics::{self, Side};
+use futures::future::join_all;
+use iroh::{EndpointAddr, EndpointId};
+use net_iroh::client::ServiceConnector;
+use protocol_base::handshake;
+use std::fmt;
+use std::net::SocketAddr;
+use std::str::FromStr;
+use tokio::net::{TcpListener, TcpStream};
+use tracing::{debug, error, info};
+
+/// Application protocol announced in the handshake for raw tunnels.
+pub const TCP_PROTOCOL: &str = "tcp";
+
+/// A remote service written as `<service>@<peer>`, or just `<service>` for a service of
+/// the default target.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct TunnelTarget {
+    pub service: String,
+    pub peer: Option<EndpointId>,
+}
+
+impl FromStr for TunnelTarget {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self> {
+        let (service, peer) = match s.split_once('@') {
+            Some((service, peer)) => {
+                let id = peer
+                    .parse::<EndpointId>()
+                    .map_err(|e| anyhow!("invalid peer id {} in {}: {}", peer, s, e))?;
+                (service, Some(id))
+            }
+            None => (s, None),
+        };
+        if service.is_empty() {
+            return Err(anyhow!("missing service name in {}", s));
+        }
+        Ok(Self {
+            service: service.to_string(),
+            peer,
+        })
+    }
+}
+
+impl fmt::Display for TunnelTarget {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match &self.peer {
+            Some(peer) => write!(f, "{}@{}", self.service, peer),
+            None => write!(f, "{}", self.service),
+        }
+    }
+}
+
+/// Run all configured tunnels until they fail. A tunnel that cannot start is logged and
+/// does not stop the others.
+pub async fn start_tunnels(
+    tunnels: Vec<TcpTunnelConfig>,
+    connector: ServiceConnector,
+    default_target: EndpointAddr,
+) {
+    let tasks = tunnels.into_iter().map(|tunnel| {
+        let connector = connector.clone();
+        let default_target = default_target.clone();
+        async move {
+            let result = match tunnel.target.parse::<TunnelTarget>() {
+                Ok(target) => {
+                    start_tunnel(tunnel.local_port, target, connector, default_target).await
+                }
+                Err(e) => Err(e),
+            };
+            if let Err(e) = result {
+                error!(
+                    "TCP tunnel on port {} to {} failed: {}",
+                    tunnel.local_port, tunnel.target, e
+                );
+            }
+        }
+    });
+    join_all(tasks).await;
+}
+
+/// Listen on `local_port` and tunnel every connection to `target`.
+pub async fn start_tunnel(
+    local_port: u16,
+    target: TunnelTarget,
+    connector: ServiceConnector,
+    default_target: EndpointAddr,
+) -> Result<()> {
+    let addr = SocketAddr::from(([127, 0, 0, 1], local_port));
+    let listener = TcpListener::bind(addr).await?;
+    let peer = match target.peer {
+        Some(id) => EndpointAddr::new(id),
+        None => default_target,
+    };
+    serve_tunnel(listener, target.service, peer, connector).await
+}
+
+/// Tunnel every connection accepted on `listener` to `service` on `peer`.
+pub async fn serve_tunnel(
+    listener: TcpListener,
+    service: String,
+    peer: EndpointAddr,
+    connector: ServiceConnector,
+) -> Result<()> {
+    info!(
+        "TCP tunnel listening on {}, target: {}@{}",
+        listener.local_addr()?,
+        service,
+        peer.id
+    );
+
+    loop {
+        let (client, cl_addr) = listener.accept().await?;
+        debug!("New tunnel connection from {} to {}", cl_addr, service);
+        let connector = connector.clone();
+        let peer = peer.clone();
+        let service = service.clone();
+        tokio::spawn(async move {
+            if let Err(e) = tunnel_connection(client, &connector, peer, &service).await {
+                debug!("tunnel connection error: {e}");
+            }
+        });
+    }
+}
+
+async fn tunnel_connection(
+    mut client: TcpStream,
+    connector: &ServiceConnector,
+    peer: EndpointAddr,
+    service: &str,
+) -> Result<()> {
+    let peer_id = peer.id;
+    let request = connector.handshake_for(&peer_id, service, TCP_PROTOCOL);
+    let mut iroh_stream = connector.open_stream(peer).await?;
+    // Nothing can be told to a raw TCP client, a refused service just closes the socket
+    handshake::request_service(&mut iroh_stream, &request).await?;
+
+    let (client_to_backend, backend_to_client) = metrics::copy_metered(
+        &mut client,
+        &mut iroh_stream,
+        Side::Client,
+        service,
+        &peer_id.to_string(),
+    )
+    .await?;
+    debug!(
+        "tunnel copied bytes {}&{}",
+        client_to_backend, backend_to_client
+    );
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use iroh::SecretKey;
+
+    #[test]
+    fn test_parse_tunnel_target() {
+        let peer = SecretKey::generate(&mut rand::rng()).public();
+
+        let target: TunnelTarget = format!("postgres@{}", peer).parse().unwrap();
+        assert_eq!(target.service, "postgres");
+        assert_eq!(target.peer, Some(peer));
+        assert_eq!(target.to_string().parse::<TunnelTarget>().unwrap(), target);
+
+        let local: TunnelTarget = "redis".parse().unwrap();
+        assert_eq!(local.peer, None);
+
+        assert!("ssh@not-a-peer".parse::<TunnelTarget>().is_err());
+        assert!(format!("@{}", peer).parse::<TunnelTarget>().is_err());
+    }
+}
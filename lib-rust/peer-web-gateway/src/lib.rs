@@ -1,28 +1,43 @@
 use anyhow::{Result, anyhow};
 use askama::Template;
-use common::iroh_utils::IrohStream;
+use common::config::IceServerConfig;
+use common::metrics::{self, Side};
 use common::protocol_utils::{
-    extract_host_from_http, extract_service_from_host, extract_sni, is_tls_client_hello,
+    extract_host_from_http, extract_peer_from_host, extract_service_from_host, extract_sni,
+    is_tls_client_hello,
 };
-use iroh::{Endpoint, EndpointAddr};
-use protocol_base::SYNEROYM_ALPN;
+use iroh::{EndpointAddr, EndpointId};
+use net_iroh::client::ServiceConnector;
+use protocol_base::forward::http_error_response;
+use protocol_base::handshake;
 use std::net::SocketAddr;
 use std::sync::Arc;
-use tokio::io::{self, AsyncWriteExt};
+use tokio::io::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt};
 use tokio::net::{TcpListener, TcpStream};
+use tokio_rustls::TlsAcceptor;
 use tracing::{debug, error, info};
 
+pub mod tls;
+
+use tls::LocalCa;
+
 #[derive(Clone)]
 struct AppState {
-    iroh: Endpoint,
+    connector: ServiceConnector,
     target: EndpointAddr,
     signaling_server_url: String,
+    /// ICE servers for the browser, as a JSON array safe to embed in a script
+    ice_servers_json: String,
+    /// Terminates TLS with certificates from the local CA. Without it, TLS connections
+    /// are tunneled to the service as they are.
+    tls_acceptor: Option<TlsAcceptor>,
 }
 
 #[derive(Template)]
 #[template(path = "peer-proxy.html")]
 struct PeerProxyTemplate<'a> {
     signaling_server_url: &'a str,
+    ice_servers_json: &'a str,
     target_peer_id: &'a str,
     http_version: &'a str,
 }
@@ -31,23 +46,28 @@ struct PeerProxyTemplate<'a> {
 #[template(path = "sw.js", escape = "none")]
 struct SwTemplate;
 
+/// Start the web gateway, tunneling through the node's own `connector`. With a `ca`,
+/// https connections are terminated by the gateway and served like plain http.
 pub async fn start(
     port: u16,
+    connector: ServiceConnector,
     target: EndpointAddr,
     signaling_server_url: String,
-    iroh_relay_url: Option<String>,
+    ice_servers: &[IceServerConfig],
+    ca: Option<Arc<LocalCa>>,
 ) -> Result<()> {
     info!(
         "Starting LocalNode Web Gateway on port {}, target: {:?}",
         port, target
     );
 
-    let endpoint = common::iroh_utils::bind_endpoint(iroh_relay_url).await?;
-
+    let tls_acceptor = ca.map(|ca| ca.acceptor()).transpose()?;
     let state = Arc::new(AppState {
-        iroh: endpoint,
+        connector,
         target,
         signaling_server_url,
+        ice_servers_json: ice_servers_json(ice_servers)?,
+        tls_acceptor,
     });
 
     let addr = SocketAddr::from(([127, 0, 0, 1], port));
@@ -66,7 +86,7 @@ pub async fn start(
     }
 }
 
-async fn handle_connection(mut client: TcpStream, state: Arc<AppState>) -> Result<()> {
+async fn handle_connection(client: TcpStream, state: Arc<AppState>) -> Result<()> {
     let mut peek_buf = vec![0u8; 4096];
     let n = client.peek(&mut peek_buf).await?;
     if n == 0 {
@@ -77,12 +97,35 @@ async fn handle_connection(mut client: TcpStream, state: Arc<AppState>) -> Resul
     if is_tls_client_hello(&peek_buf[..n]) {
         debug!("Detected TLS connection");
         let hostname = extract_sni(&peek_buf[..n])?;
-        // TODO. Currently tunneling to iroh. Future: handle https://xxx and return the proxy+sw.js (requires certs)
-        return tunnel_to_iroh(client, &hostname, state).await;
+        let Some(acceptor) = state.tls_acceptor.clone() else {
+            return tunnel_to_iroh(client, &[], &hostname, false, state).await;
+        };
+
+        // The decrypted stream cannot be peeked, what was read is replayed to the tunnel
+        let mut stream = acceptor.accept(client).await?;
+        let head = read_head(&mut stream).await?;
+        if head.is_empty() {
+            return Ok(());
+        }
+        return handle_http(stream, &head, &head, state).await;
     }
 
-    // 2. Try HTTP
-    let http_info = parse_http_peek(&peek_buf[..n]);
+    // 2. Plain HTTP, still unread in the socket
+    handle_http(client, &peek_buf[..n], &[], state).await
+}
+
+/// Serve an HTTP connection whose start is `head`. `consumed` are the bytes already read
+/// from `client`, they are sent ahead of the rest when tunneling.
+async fn handle_http<S>(
+    mut client: S,
+    head: &[u8],
+    consumed: &[u8],
+    state: Arc<AppState>,
+) -> Result<()>
+where
+    S: AsyncRead + AsyncWrite + Unpin,
+{
+    let http_info = parse_http_peek(head);
 
     if let Ok((_method, path, host, has_loop_header, is_websocket)) = http_info {
         // debug!("Detected HTTP: {} {} (Host: {}, WS: {})", _method, path, host, is_websocket);
@@ -96,7 +139,7 @@ async fn handle_connection(mut client: TcpStream, state: Arc<AppState>) -> Resul
         if is_websocket {
             // Tunnel WebSockets
             debug!("Tunneling WebSocket request for host: {}", host);
-            return tunnel_to_iroh(client, &host, state).await;
+            return tunnel_to_iroh(client, consumed, &host, true, state).await;
         }
 
         if path == "/__syneroym/sw.js" {
@@ -111,10 +154,10 @@ async fn handle_connection(mut client: TcpStream, state: Arc<AppState>) -> Resul
 
     // 3. Fallback: just try to extract host (maybe it was partial HTTP or something)
     // or fail.
-    match extract_host_from_http(&peek_buf[..n]) {
+    match extract_host_from_http(head) {
         Ok(host) => {
             debug!("Fallback: Extracted host {}, tunneling", host);
-            tunnel_to_iroh(client, &host, state).await
+            tunnel_to_iroh(client, consumed, &host, true, state).await
         }
         Err(_) => {
             // Could not identify protocol or host
@@ -125,10 +168,22 @@ async fn handle_connection(mut client: TcpStream, state: Arc<AppState>) -> Resul
     }
 }
 
-async fn serve_index(mut client: TcpStream, host: &str, state: Arc<AppState>) -> Result<()> {
-    let peer_id = extract_peer_id_from_host(host);
+async fn serve_index<S: AsyncWrite + Unpin>(
+    mut client: S,
+    host: &str,
+    state: Arc<AppState>,
+) -> Result<()> {
+    let peer_id = match extract_peer_id_from_host(host, &state.target.id) {
+        Ok(peer_id) => peer_id,
+        Err(e) => {
+            let response = http_error_response(400, "Bad Request", &e.to_string());
+            client.write_all(response.as_bytes()).await?;
+            return Err(e);
+        }
+    };
     let template = PeerProxyTemplate {
         signaling_server_url: &state.signaling_server_url,
+        ice_servers_json: &state.ice_servers_json,
         target_peer_id: &peer_id,
         http_version: "HTTP/1.1",
     };
@@ -151,7 +206,7 @@ async fn serve_index(mut client: TcpStream, host: &str, state: Arc<AppState>) ->
     Ok(())
 }
 
-async fn serve_sw(mut client: TcpStream) -> Result<()> {
+async fn serve_sw<S: AsyncWrite + Unpin>(mut client: S) -> Result<()> {
     let template = SwTemplate;
     match template.render() {
         Ok(content) => {
@@ -171,25 +226,46 @@ async fn serve_sw(mut client: TcpStream) -> Result<()> {
     Ok(())
 }
 
-async fn tunnel_to_iroh(mut client: TcpStream, hostname: &str, state: Arc<AppState>) -> Result<()> {
+/// Tunnel the client connection to the service named in `hostname`, starting with the
+/// `consumed` bytes already read from it. `plain_http` says whether a handshake failure
+/// can be reported back as an HTTP response.
+async fn tunnel_to_iroh<S>(
+    mut client: S,
+    consumed: &[u8],
+    hostname: &str,
+    plain_http: bool,
+    state: Arc<AppState>,
+) -> Result<()>
+where
+    S: AsyncRead + AsyncWrite + Unpin,
+{
     let svc_name = extract_service_from_host(hostname)?;
     debug!("Tunneling to service: {}", svc_name);
 
-    // Connect to Iroh
-    let connection = state
-        .iroh
-        .connect(state.target.clone(), SYNEROYM_ALPN)
-        .await?;
-    let (send, recv) = connection.open_bi().await?;
+    // Open a stream over a (pooled) Iroh connection
+    let mut iroh_stream = state.connector.open_stream(state.target.clone()).await?;
 
     // Handshake
-    let svc_raw = svc_name.as_bytes();
-    let mut iroh_stream = IrohStream::new(send, recv);
-    iroh_stream.write_u8(svc_raw.len() as u8).await?;
-    iroh_stream.write_all(svc_raw).await?;
+    let request = state
+        .connector
+        .handshake_for(&state.target.id, &svc_name, "http");
+    if let Err(e) = handshake::request_service(&mut iroh_stream, &request).await {
+        if plain_http {
+            client.write_all(e.to_http_response().as_bytes()).await?;
+        }
+        return Err(e.into());
+    }
+    iroh_stream.write_all(consumed).await?;
 
     // Proxy
-    let (c2s, s2c) = io::copy_bidirectional(&mut client, &mut iroh_stream).await?;
+    let (c2s, s2c) = metrics::copy_metered(
+        &mut client,
+        &mut iroh_stream,
+        Side::Client,
+        &svc_name,
+        &state.target.id.to_string(),
+    )
+    .await?;
     debug!(
         "Tunnel finished: client->server={}, server->client={}",
         c2s, s2c
@@ -199,6 +275,21 @@ async fn tunnel_to_iroh(mut client: TcpStream, hostname: &str, state: Arc<AppSta
 
 // Helpers
 
+/// Read from `stream` until the end of the request head, or until as much as a peek
+/// would see.
+async fn read_head<S: AsyncRead + Unpin>(stream: &mut S) -> Result<Vec<u8>> {
+    let mut head = Vec::new();
+    let mut chunk = [0u8; 4096];
+    while head.len() < 4096 && !head.windows(4).any(|w| w == b"\r\n\r\n") {
+        let n = stream.read(&mut chunk).await?;
+        if n == 0 {
+            break;
+        }
+        head.extend_from_slice(&chunk[..n]);
+    }
+    Ok(head)
+}
+
 fn parse_http_peek(buf: &[u8]) -> Result<(String, String, String, bool, bool)> {
     let text = String::from_utf8_lossy(buf);
     let mut lines = text.lines();
@@ -238,10 +329,60 @@ fn parse_http_peek(buf: &[u8]) -> Result<(String, String, String, bool, bool)> {
     Ok((method, path, host, has_loop, is_websocket))
 }
 
-fn extract_peer_id_from_host(host: &str) -> String {
-    let hostname = host.split(':').next().unwrap_or(host);
-    match hostname.split_once('.') {
-        Some((_, rest)) => rest.to_string(),
-        None => hostname.to_string(),
+/// Serialize `ice_servers` for the page's script. `</` is escaped so that no value can
+/// close the script element.
+fn ice_servers_json(ice_servers: &[IceServerConfig]) -> Result<String> {
+    Ok(serde_json::to_string(ice_servers)?.replace("</", "<\\/"))
+}
+
+/// The signaling id of the node serving `host`, which is its EndpointId: the peer label
+/// of `<service>.<peer>.localhost`, or this node for `<service>.localhost`.
+fn extract_peer_id_from_host(host: &str, own_id: &EndpointId) -> Result<String> {
+    match extract_peer_from_host(host) {
+        Some(label) => {
+            let id = label
+                .parse::<EndpointId>()
+                .map_err(|e| anyhow!("invalid peer id {} in host {}: {}", label, host, e))?;
+            Ok(id.to_string())
+        }
+        None => Ok(own_id.to_string()),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use iroh::SecretKey;
+
+    #[test]
+    fn test_extract_peer_id_from_host() {
+        let own = SecretKey::generate(&mut rand::rng()).public();
+        let peer = SecretKey::generate(&mut rand::rng()).public();
+        let label = common::iroh_utils::endpoint_id_label(&peer);
+
+        // The id the peer registers with on the signaling server
+        assert_eq!(
+            extract_peer_id_from_host(&format!("demo3001.{}.localhost:8001", label), &own).unwrap(),
+            peer.to_string()
+        );
+        assert_eq!(
+            extract_peer_id_from_host("demo3001.localhost:8001", &own).unwrap(),
+            own.to_string()
+        );
+        assert!(extract_peer_id_from_host("demo3001.nope.localhost", &own).is_err());
+    }
+
+    #[test]
+    fn test_ice_servers_json() {
+        let servers = [IceServerConfig {
+            urls: vec!["turn:turn.example.com:3478".to_string()],
+            username: Some("user".to_string()),
+            credential: Some("</script>".to_string()),
+        }];
+        assert_eq!(
+            ice_servers_json(&servers).unwrap(),
+            r#"[{"urls":["turn:turn.example.com:3478"],"username":"user","credential":"<\/script>"}]"#
+        );
+        assert_eq!(ice_servers_json(&[]).unwrap(), "[]");
     }
 }
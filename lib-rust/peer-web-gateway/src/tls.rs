@@ -0,0 +1,282 @@
+//! Per-node local certificate authority, so the gateway can terminate TLS.
+//!
+//! The CA is generated on first use and kept in a directory as `ca.pem` and `ca.key`.
+//! Leaf certificates for `*.localhost` names are minted from the SNI of each connection
+//! and cached for the life of the process. Browsers accept them once `ca.pem` is trusted,
+//! see `syneroym-cli export-ca`.
+//!
+//! The CA is name constrained to `localhost`, certificates it signs for other names do
+//! not verify.
+
+use anyhow::{Context, Result, anyhow};
+use rcgen::{
+    BasicConstraints, CertificateParams, DnType, ExtendedKeyUsagePurpose, GeneralSubtree, IsCa,
+    Issuer, KeyPair, KeyUsagePurpose, NameConstraints,
+};
+use rustls::crypto::ring::sign::any_supported_type;
+use rustls::pki_types::pem::PemObject;
+use rustls::pki_types::{CertificateDer, PrivateKeyDer, PrivatePkcs8KeyDer};
+use rustls::server::{ClientHello, ResolvesServerCert};
+use rustls::sign::CertifiedKey;
+use std::collections::HashMap;
+use std::fmt;
+use std::fs;
+use std::path::Path;
+use std::sync::{Arc, Mutex};
+use time::{Duration, OffsetDateTime};
+use tokio_rustls::TlsAcceptor;
+use tracing::{debug, info, warn};
+
+const CA_CERT_FILE: &str = "ca.pem";
+const CA_KEY_FILE: &str = "ca.key";
+const CA_VALIDITY: Duration = Duration::days(3650);
+/// Kept under the 398 day limit some clients enforce for leaf certificates.
+const LEAF_VALIDITY: Duration = Duration::days(397);
+/// Only names under this domain get certificates, the CA must not vouch for real sites.
+const LOCAL_DOMAIN: &str = "localhost";
+
+pub struct LocalCa {
+    cert_pem: String,
+    cert_der: CertificateDer<'static>,
+    issuer: Issuer<'static, KeyPair>,
+    leaves: Mutex<HashMap<String, Arc<CertifiedKey>>>,
+}
+
+impl fmt::Debug for LocalCa {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("LocalCa").finish_non_exhaustive()
+    }
+}
+
+impl LocalCa {
+    /// Load the CA from `dir`, generating it on first use.
+    pub fn load_or_create(dir: &Path) -> Result<Self> {
+        let cert_path = dir.join(CA_CERT_FILE);
+        let key_path = dir.join(CA_KEY_FILE);
+
+        if cert_path.exists() && key_path.exists() {
+            let cert_pem = fs::read_to_string(&cert_path)
+                .with_context(|| format!("failed to read CA certificate {:?}", cert_path))?;
+            let key_pem = fs::read_to_string(&key_path)
+                .with_context(|| format!("failed to read CA key {:?}", key_path))?;
+            let key = KeyPair::from_pem(&key_pem)
+                .with_context(|| format!("invalid CA key in {:?}", key_path))?;
+            let issuer = Issuer::from_ca_cert_pem(&cert_pem, key)
+                .with_context(|| format!("invalid CA certificate in {:?}", cert_path))?;
+            let cert_der = CertificateDer::from_pem_slice(cert_pem.as_bytes())
+                .with_context(|| format!("invalid CA certificate in {:?}", cert_path))?;
+            debug!("Loaded local CA from {:?}", dir);
+            return Ok(Self::new(cert_pem, cert_der, issuer));
+        }
+
+        let key = KeyPair::generate()?;
+        let mut params = CertificateParams::default();
+        params
+            .distinguished_name
+            .push(DnType::CommonName, "Syneroym Local CA");
+        params
+            .distinguished_name
+            .push(DnType::OrganizationName, "Syneroym");
+        params.is_ca = IsCa::Ca(BasicConstraints::Constrained(0));
+        // Clients reject anything else it signs, should the key ever leak
+        params.name_constraints = Some(NameConstraints {
+            permitted_subtrees: vec![GeneralSubtree::DnsName(LOCAL_DOMAIN.to_string())],
+            excluded_subtrees: vec![],
+        });
+        params.key_usages = vec![
+            KeyUsagePurpose::KeyCertSign,
+            KeyUsagePurpose::CrlSign,
+            KeyUsagePurpose::DigitalSignature,
+        ];
+        let now = OffsetDateTime::now_utc();
+        params.not_before = now - Duration::days(1);
+        params.not_after = now + CA_VALIDITY;
+        let cert = params.self_signed(&key)?;
+
+        fs::create_dir_all(dir)?;
+        fs::write(&cert_path, cert.pem())
+            .with_context(|| format!("failed to write CA certificate {:?}", cert_path))?;
+        let mut options = fs::OpenOptions::new();
+        options.write(true).create(true).truncate(true);
+        #[cfg(unix)]
+        {
+            use std::os::unix::fs::OpenOptionsExt;
+            options.mode(0o600);
+        }
+        let mut file = options
+            .open(&key_path)
+            .with_context(|| format!("failed to create CA key file {:?}", key_path))?;
+        std::io::Write::write_all(&mut file, key.serialize_pem().as_bytes())?;
+        info!("Generated local CA in {:?}", dir);
+
+        Ok(Self::new(
+            cert.pem(),
+            cert.der().clone(),
+            Issuer::new(params, key),
+        ))
+    }
+
+    fn new(
+        cert_pem: String,
+        cert_der: CertificateDer<'static>,
+        issuer: Issuer<'static, KeyPair>,
+    ) -> Self {
+        Self {
+            cert_pem,
+            cert_der,
+            issuer,
+            leaves: Mutex::new(HashMap::new()),
+        }
+    }
+
+    /// The CA certificate, for clients to trust.
+    pub fn cert_pem(&self) -> &str {
+        &self.cert_pem
+    }
+
+    /// A certificate for `host`, minted on first use.
+    pub fn leaf_for(&self, host: &str) -> Result<Arc<CertifiedKey>> {
+        let host = host.trim_end_matches('.').to_ascii_lowercase();
+        if host != LOCAL_DOMAIN && !host.ends_with(&format!(".{}", LOCAL_DOMAIN)) {
+            return Err(anyhow!("refusing to issue a certificate for {}", host));
+        }
+        if let Some(leaf) = self.leaves.lock().unwrap().get(&host) {
+            return Ok(leaf.clone());
+        }
+
+        let leaf = Arc::new(self.issue(&host)?);
+        debug!("Issued certificate for {}", host);
+        self.leaves.lock().unwrap().insert(host, leaf.clone());
+        Ok(leaf)
+    }
+
+    /// Sign a new certificate for `host`, whatever its domain.
+    fn issue(&self, host: &str) -> Result<CertifiedKey> {
+        let key = KeyPair::generate()?;
+        let mut params = CertificateParams::new(vec![host.to_string()])?;
+        params.distinguished_name.push(DnType::CommonName, host);
+        params.key_usages = vec![
+            KeyUsagePurpose::DigitalSignature,
+            KeyUsagePurpose::KeyEncipherment,
+        ];
+        params.extended_key_usages = vec![ExtendedKeyUsagePurpose::ServerAuth];
+        let now = OffsetDateTime::now_utc();
+        params.not_before = now - Duration::days(1);
+        params.not_after = now + LEAF_VALIDITY;
+        let cert = params.signed_by(&key, &self.issuer)?;
+
+        let key_der = PrivateKeyDer::Pkcs8(PrivatePkcs8KeyDer::from(key.serialize_der()));
+        let signing_key = any_supported_type(&key_der)?;
+        Ok(CertifiedKey::new(vec![cert.der().clone()], signing_key))
+    }
+
+    /// A TLS acceptor that presents a certificate for the SNI of each connection.
+    pub fn acceptor(self: Arc<Self>) -> Result<TlsAcceptor> {
+        let provider = Arc::new(rustls::crypto::ring::default_provider());
+        let mut config = rustls::ServerConfig::builder_with_provider(provider)
+            .with_safe_default_protocol_versions()?
+            .with_no_client_auth()
+            .with_cert_resolver(Arc::new(SniResolver { ca: self }));
+        config.alpn_protocols = vec![b"http/1.1".to_vec()];
+        Ok(TlsAcceptor::from(Arc::new(config)))
+    }
+
+    /// The CA certificate in DER form.
+    pub fn cert_der(&self) -> &CertificateDer<'static> {
+        &self.cert_der
+    }
+}
+
+#[derive(Debug)]
+struct SniResolver {
+    ca: Arc<LocalCa>,
+}
+
+impl ResolvesServerCert for SniResolver {
+    fn resolve(&self, client_hello: ClientHello<'_>) -> Option<Arc<CertifiedKey>> {
+        let name = client_hello.server_name()?;
+        match self.ca.leaf_for(name) {
+            Ok(leaf) => Some(leaf),
+            Err(e) => {
+                warn!("No certificate for {}: {}", name, e);
+                None
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use rustls::client::WebPkiServerVerifier;
+    use rustls::client::danger::ServerCertVerifier;
+    use rustls::pki_types::{ServerName, UnixTime};
+    use tokio::io::{AsyncReadExt, AsyncWriteExt};
+    use tokio_rustls::TlsConnector;
+
+    #[tokio::test]
+    async fn test_terminates_tls_with_minted_certificate() {
+        let dir = std::env::temp_dir().join(format!("syneroym-ca-{}", std::process::id()));
+        let ca = LocalCa::load_or_create(&dir).unwrap();
+        // The CA is stable across restarts
+        let reloaded = Arc::new(LocalCa::load_or_create(&dir).unwrap());
+        assert_eq!(ca.cert_pem(), reloaded.cert_pem());
+        assert!(reloaded.leaf_for("example.com").is_err());
+
+        let mut roots = rustls::RootCertStore::empty();
+        roots.add(ca.cert_der().clone()).unwrap();
+        let provider = Arc::new(rustls::crypto::ring::default_provider());
+        let client_config = rustls::ClientConfig::builder_with_provider(provider)
+            .with_safe_default_protocol_versions()
+            .unwrap()
+            .with_root_certificates(roots)
+            .with_no_client_auth();
+        let connector = TlsConnector::from(Arc::new(client_config));
+        let acceptor = reloaded.acceptor().unwrap();
+
+        let (client, server) = tokio::io::duplex(64 * 1024);
+        let server = tokio::spawn(async move {
+            let mut stream = acceptor.accept(server).await.unwrap();
+            let mut buf = [0u8; 5];
+            stream.read_exact(&mut buf).await.unwrap();
+            stream.write_all(&buf).await.unwrap();
+            stream.shutdown().await.unwrap();
+        });
+
+        let name = ServerName::try_from("demo3001.abc.localhost").unwrap();
+        let mut stream = connector.connect(name, client).await.unwrap();
+        stream.write_all(b"hello").await.unwrap();
+        let mut echoed = Vec::new();
+        stream.read_to_end(&mut echoed).await.unwrap();
+        assert_eq!(echoed, b"hello");
+        server.await.unwrap();
+
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn test_certificates_outside_localhost_do_not_verify() {
+        let dir = std::env::temp_dir().join(format!("syneroym-ca-nc-{}", std::process::id()));
+        let ca = LocalCa::load_or_create(&dir).unwrap();
+        let mut roots = rustls::RootCertStore::empty();
+        roots.add(ca.cert_der().clone()).unwrap();
+        let provider = Arc::new(rustls::crypto::ring::default_provider());
+        let verifier = WebPkiServerVerifier::builder_with_provider(Arc::new(roots), provider)
+            .build()
+            .unwrap();
+
+        for (host, trusted) in [("demo3001.abc.localhost", true), ("example.com", false)] {
+            let leaf = ca.issue(host).unwrap();
+            let verified = verifier.verify_server_cert(
+                &leaf.cert[0],
+                &[],
+                &ServerName::try_from(host).unwrap(),
+                &[],
+                UnixTime::now(),
+            );
+            assert_eq!(verified.is_ok(), trusted, "{}: {:?}", host, verified);
+        }
+
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+}
@@ -0,0 +1,308 @@
+//! Serving of accepted service streams, the same whichever transport carried them.
+
+use crate::ProtocolHandler;
+use crate::forward::{StreamContext, forward_raw};
+use crate::handshake::{self, HandshakeError, HandshakeReply, HandshakeStatus, StreamHandshake};
+use crate::routing::{Caller, RoutingTable};
+use common::capability::{TokenError, unix_now, verify_token};
+use common::iroh_utils::AsyncStream;
+use common::metrics::{self, ANONYMOUS_PEER, ByteCounters, Counted, Side};
+use iroh::EndpointId;
+use std::sync::Arc;
+use std::time::Instant;
+use store_interface::ServiceRecord;
+use tokio::io::{AsyncRead, AsyncWrite, AsyncWriteExt};
+use tokio::net::TcpStream;
+use tracing::{debug, info, warn};
+
+/// Reads the handshake of accepted streams, resolves the service they ask for and hands
+/// them to the protocol handler of that service. Cheap to clone.
+#[derive(Debug, Clone)]
+pub struct StreamDispatcher {
+    handlers: Vec<Arc<dyn ProtocolHandler>>,
+    routes: RoutingTable,
+    /// Issuer of the capability tokens this node accepts.
+    node_id: EndpointId,
+}
+
+impl StreamDispatcher {
+    pub fn new(
+        handlers: Vec<Arc<dyn ProtocolHandler>>,
+        routes: RoutingTable,
+        node_id: EndpointId,
+    ) -> Self {
+        Self {
+            handlers,
+            routes,
+            node_id,
+        }
+    }
+
+    /// Serve `stream` for `caller` until it ends.
+    pub async fn serve<S>(&self, stream: S, caller: Caller) -> Result<(), HandshakeError>
+    where
+        S: AsyncRead + AsyncWrite + Unpin + Send + 'static,
+    {
+        self.serve_with(stream, caller, |_, _| ()).await
+    }
+
+    /// Like [`serve`](Self::serve), calling `on_accept` with the service and the byte
+    /// counters of the stream once the backend is connected. What it returns is kept
+    /// until the stream ends, e.g. a guard listing the stream.
+    pub async fn serve_with<S, G>(
+        &self,
+        mut stream: S,
+        caller: Caller,
+        on_accept: impl FnOnce(&ServiceRecord, Arc<ByteCounters>) -> G,
+    ) -> Result<(), HandshakeError>
+    where
+        S: AsyncRead + AsyncWrite + Unpin + Send + 'static,
+    {
+        // --- Read handshake ---
+        let request = match handshake::read_request(&mut stream).await {
+            Ok(request) => request,
+            Err(e @ HandshakeError::UnsupportedVersion(_)) => {
+                return Err(reject(&mut stream, HandshakeStatus::UnsupportedVersion, e).await);
+            }
+            Err(e @ (HandshakeError::Malformed(_) | HandshakeError::FrameTooLarge(_))) => {
+                return Err(reject(&mut stream, HandshakeStatus::BadRequest, e).await);
+            }
+            Err(e) => return Err(e),
+        };
+        debug!(
+            "stream requested service {} (protocol: {:?})",
+            request.service_id, request.app_protocol
+        );
+
+        // --- Check the capability token, if one was presented ---
+        let caller = match authorize(&request, &self.node_id, caller) {
+            Ok(caller) => caller,
+            Err(e) => return Err(reject(&mut stream, HandshakeStatus::Unauthorized, e).await),
+        };
+
+        // --- Resolve the route, enforcing the service's access policy ---
+        let route = match self.routes.resolve_for(&request.service_id, &caller) {
+            Ok(route) => route,
+            Err(e) => return Err(reject(&mut stream, e.status(), e).await),
+        };
+
+        // --- Connect to the backend ---
+        let backend = match TcpStream::connect(&route.backend_addr).await {
+            Ok(backend) => backend,
+            Err(e) => {
+                metrics::record_backend_failure(&route.service.service_key);
+                return Err(reject(&mut stream, HandshakeStatus::BackendDown, e).await);
+            }
+        };
+        // Only what follows the handshake counts as the stream's bytes
+        handshake::write_reply(&mut stream, &HandshakeReply::ok()).await?;
+        let peer = caller.endpoint_id.as_deref().unwrap_or(ANONYMOUS_PEER);
+        let counters = Arc::new(ByteCounters::metered(
+            Side::Server,
+            &route.service.service_key,
+            peer,
+        ));
+        let _accepted = on_accept(&route.service, counters.clone());
+        let started = Instant::now();
+        let client: Box<dyn AsyncStream> = Box::new(Counted::new(stream, counters));
+
+        // --- Hand the stream to the service's protocol handler ---
+        let handler = self
+            .handlers
+            .iter()
+            .find(|h| h.protocol_id() == route.service.app_layer_protocol)
+            .cloned();
+        let ctx = StreamContext {
+            service: route.service,
+            caller,
+        };
+        let forwarded = match handler {
+            Some(handler) => handler.forward(client, backend, &ctx).await,
+            None => forward_raw(client, backend, &ctx).await,
+        };
+        if let Err(e) = forwarded {
+            debug!("forwarding to {} ended: {:#}", ctx.service.service_key, e);
+        }
+        metrics::record_stream(
+            Side::Server,
+            &ctx.service.service_key,
+            ctx.caller.endpoint_id.as_deref().unwrap_or(ANONYMOUS_PEER),
+            started.elapsed(),
+        );
+
+        Ok(())
+    }
+}
+
+/// Reply with a rejection, returning it as the stream's error.
+async fn reject<S, E>(stream: &mut S, status: HandshakeStatus, cause: E) -> HandshakeError
+where
+    S: AsyncWrite + Unpin,
+    E: std::fmt::Display,
+{
+    warn!("Rejecting stream: {}", cause);
+    let message = cause.to_string();
+    let reply = HandshakeReply::error(status, message.clone());
+    if let Err(e) = handshake::write_reply(stream, &reply).await {
+        debug!("failed to send handshake rejection: {}", e);
+    }
+    let _ = stream.shutdown().await;
+    HandshakeError::Rejected { status, message }
+}
+
+/// Verify the capability token presented in the handshake, if any, and attach its
+/// claims to the caller. Tokens are checked offline against this node's own key.
+fn authorize(
+    request: &StreamHandshake,
+    node_id: &EndpointId,
+    caller: Caller,
+) -> Result<Caller, TokenError> {
+    let Some(token) = &request.auth_token else {
+        return Ok(caller);
+    };
+    let claims = verify_token(token, node_id, unix_now())?;
+    if claims.service != request.service_id {
+        return Err(TokenError::WrongService(claims.service));
+    }
+    if let Some(holder) = &claims.holder
+        && !caller
+            .endpoint_id
+            .as_deref()
+            .is_some_and(|id| id.eq_ignore_ascii_case(holder))
+    {
+        return Err(TokenError::WrongHolder);
+    }
+    info!(
+        "{} presented a capability token for {} (subject: {:?})",
+        caller, claims.service, claims.subject
+    );
+    Ok(caller.with_capability(claims))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use anyhow::Result;
+    use app_host::ServiceRpc;
+    use async_trait::async_trait;
+    use std::collections::HashMap;
+    use store_interface::AccessMode;
+    use tokio::io::AsyncReadExt;
+    use tokio::net::TcpListener;
+
+    /// Tags the backend's answer, to tell it apart from a raw copy.
+    #[derive(Debug)]
+    struct Tagging;
+
+    #[async_trait]
+    impl ProtocolHandler for Tagging {
+        fn protocol_id(&self) -> String {
+            "tagged".to_string()
+        }
+
+        async fn setup(&self, _services: HashMap<String, ServiceRpc>) -> Result<()> {
+            Ok(())
+        }
+
+        async fn forward(
+            &self,
+            mut client: Box<dyn AsyncStream>,
+            mut backend: TcpStream,
+            _ctx: &StreamContext,
+        ) -> Result<()> {
+            let mut answer = String::new();
+            backend.read_to_string(&mut answer).await?;
+            client
+                .write_all(format!("tagged:{}", answer).as_bytes())
+                .await?;
+            client.shutdown().await?;
+            Ok(())
+        }
+    }
+
+    fn service(key: &str, protocol: &str, backend: &str) -> ServiceRecord {
+        ServiceRecord {
+            service_key: key.to_string(),
+            app_layer_protocol: protocol.to_string(),
+            service_image_manifest_ref: format!("local-{}/{}", protocol, key),
+            backend_target: Some(backend.to_string()),
+            access_policy: Default::default(),
+            enabled: true,
+            identity_headers: false,
+        }
+    }
+
+    #[tokio::test]
+    async fn test_dispatch_to_the_service_handler() {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let backend = listener.local_addr().unwrap().to_string();
+        tokio::spawn(async move {
+            while let Ok((mut socket, _)) = listener.accept().await {
+                let _ = socket.write_all(b"hello").await;
+            }
+        });
+        let mut private = service("private", "tcp", &backend);
+        private.access_policy.mode = AccessMode::AllowList;
+        let routes = RoutingTable::from_services(&[
+            service("tagged", "tagged", &backend),
+            service("raw", "tcp", &backend),
+            private,
+        ]);
+        let node_id = iroh::SecretKey::from_bytes(&[7; 32]).public();
+        let dispatcher = StreamDispatcher::new(vec![Arc::new(Tagging)], routes, node_id);
+
+        for (service, expected) in [("tagged", "tagged:hello"), ("raw", "hello")] {
+            let (mut client, server) = tokio::io::duplex(1024);
+            let dispatcher = dispatcher.clone();
+            tokio::spawn(async move { dispatcher.serve(server, Caller::anonymous()).await });
+            handshake::request_service(&mut client, &StreamHandshake::new(service, ""))
+                .await
+                .unwrap();
+            let mut answer = String::new();
+            client.read_to_string(&mut answer).await.unwrap();
+            assert_eq!(answer, expected);
+        }
+
+        let (mut client, server) = tokio::io::duplex(1024);
+        let served = tokio::spawn({
+            let dispatcher = dispatcher.clone();
+            async move { dispatcher.serve(server, Caller::anonymous()).await }
+        });
+        let err = handshake::request_service(&mut client, &StreamHandshake::new("private", ""))
+            .await
+            .unwrap_err();
+        assert!(matches!(
+            err,
+            HandshakeError::Rejected {
+                status: HandshakeStatus::Unauthorized,
+                ..
+            }
+        ));
+        assert!(served.await.unwrap().is_err());
+    }
+
+    #[tokio::test]
+    async fn test_oversized_handshake_is_a_bad_request() {
+        let node_id = iroh::SecretKey::from_bytes(&[7; 32]).public();
+        let dispatcher = StreamDispatcher::new(vec![], RoutingTable::default(), node_id);
+        let (mut client, server) = tokio::io::duplex(1024);
+        let served =
+            tokio::spawn(async move { dispatcher.serve(server, Caller::anonymous()).await });
+
+        // Only the header is sent, the body is refused before it is read
+        client.write_u8(handshake::HANDSHAKE_VERSION).await.unwrap();
+        client
+            .write_u32(handshake::MAX_HANDSHAKE_LEN as u32 + 1)
+            .await
+            .unwrap();
+        let reply = handshake::read_reply(&mut client).await.unwrap();
+        assert_eq!(reply.status, HandshakeStatus::BadRequest);
+        assert!(matches!(
+            served.await.unwrap(),
+            Err(HandshakeError::Rejected {
+                status: HandshakeStatus::BadRequest,
+                ..
+            })
+        ));
+    }
+}
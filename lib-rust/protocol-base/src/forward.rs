@@ -0,0 +1,69 @@
+//! Forwarding of accepted service streams to their backends.
+
+use crate::routing::Caller;
+use anyhow::{Result, anyhow};
+use common::iroh_utils::AsyncStream;
+use store_interface::ServiceRecord;
+use tokio::io::AsyncWriteExt;
+use tokio::net::TcpStream;
+use tracing::{error, info, warn};
+
+/// Everything the node knows about a stream once its handshake was accepted.
+#[derive(Debug, Clone)]
+pub struct StreamContext {
+    pub service: ServiceRecord,
+    /// The verified caller, including the capability token it presented.
+    pub caller: Caller,
+}
+
+/// A minimal HTTP/1.1 response that closes the connection.
+pub fn http_error_response(code: u16, reason: &str, body: &str) -> String {
+    format!(
+        "HTTP/1.1 {} {}\r\nContent-Type: text/plain\r\nContent-Length: {}\r\nConnection: close\r\n\r\n{}",
+        code,
+        reason,
+        body.len(),
+        body
+    )
+}
+
+/// Copy bytes between the client and the backend unchanged.
+///
+/// Raw bytes cannot be checked request by request, so callers holding a capability token
+/// that restricts methods are refused. HTTP services forward those through the protocol
+/// handler, which checks every request.
+pub async fn forward_raw(
+    mut client: Box<dyn AsyncStream>,
+    mut backend: TcpStream,
+    ctx: &StreamContext,
+) -> Result<()> {
+    if let Some(claims) = ctx.caller.capability.as_ref()
+        && !claims.methods.is_empty()
+    {
+        warn!(
+            "Capability token for {} restricts methods, which raw forwarding cannot enforce",
+            claims.service
+        );
+        let body = "capability tokens restricting methods need an HTTP aware handler";
+        if ctx.service.app_layer_protocol == "http" {
+            let _ = client
+                .write_all(http_error_response(403, "Forbidden", body).as_bytes())
+                .await;
+        }
+        let _ = client.shutdown().await;
+        return Err(anyhow!(body));
+    }
+
+    match tokio::io::copy_bidirectional(&mut backend, &mut client).await {
+        Ok((client_to_backend, backend_to_client)) => {
+            info!(
+                "--> wrote to service {} bytes, <-- wrote back to iroh {} bytes",
+                client_to_backend, backend_to_client
+            );
+        }
+        Err(e) => {
+            error!("stream error: {e:?}");
+        }
+    }
+    Ok(())
+}
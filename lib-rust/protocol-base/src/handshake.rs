@@ -0,0 +1,279 @@
+//! Stream handshake exchanged at the start of every tunnelled stream.
+//!
+//! Both the request and the reply are sent as a frame of
+//! `[version: u8][payload length: u32 BE][JSON payload]`. New optional fields can be
+//! added to the payloads without a version bump, since unknown fields are ignored;
+//! the version only changes when the framing itself changes.
+
+use serde::{Deserialize, Serialize};
+use std::collections::HashMap;
+use thiserror::Error;
+use tokio::io::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt};
+
+/// Current handshake version.
+pub const HANDSHAKE_VERSION: u8 = 1;
+
+/// Upper bound for a handshake payload, to reject garbage before allocating for it.
+pub const MAX_HANDSHAKE_LEN: usize = 64 * 1024;
+
+#[derive(Debug, Error)]
+pub enum HandshakeError {
+    #[error("handshake io error: {0}")]
+    Io(#[from] std::io::Error),
+    #[error("unsupported handshake version {0}")]
+    UnsupportedVersion(u8),
+    #[error("handshake frame too large: {0} bytes")]
+    FrameTooLarge(usize),
+    #[error("malformed handshake: {0}")]
+    Malformed(#[from] serde_json::Error),
+    #[error("service request rejected ({status:?}): {message}")]
+    Rejected {
+        status: HandshakeStatus,
+        message: String,
+    },
+}
+
+impl HandshakeError {
+    /// A minimal HTTP/1.1 response describing this error, for plain HTTP clients.
+    pub fn to_http_response(&self) -> String {
+        let (code, reason) = match self {
+            HandshakeError::Rejected { status, .. } => status.http_status(),
+            _ => (502, "Bad Gateway"),
+        };
+        crate::forward::http_error_response(code, reason, &self.to_string())
+    }
+}
+
+/// Sent by the client to select the service a stream is for.
+#[derive(Debug, Clone, PartialEq, Default, Serialize, Deserialize)]
+pub struct StreamHandshake {
+    /// Service to connect to.
+    pub service_id: String,
+    /// Application layer protocol the client intends to speak (e.g. "http", "tcp").
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub app_protocol: Option<String>,
+    /// Optional credential presented for access to the service.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub auth_token: Option<String>,
+    /// Free-form key/value pairs for extensions.
+    #[serde(default, skip_serializing_if = "HashMap::is_empty")]
+    pub metadata: HashMap<String, String>,
+}
+
+impl StreamHandshake {
+    pub fn new(service_id: impl Into<String>, app_protocol: impl Into<String>) -> Self {
+        Self {
+            service_id: service_id.into(),
+            app_protocol: Some(app_protocol.into()),
+            ..Default::default()
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum HandshakeStatus {
+    Ok,
+    UnknownService,
+    Unauthorized,
+    BackendDown,
+    UnsupportedVersion,
+    BadRequest,
+    /// A status sent by a newer peer that this version does not know about.
+    #[serde(other)]
+    Unknown,
+}
+
+impl HandshakeStatus {
+    /// HTTP status code and reason for reporting this status to HTTP clients.
+    pub fn http_status(&self) -> (u16, &'static str) {
+        match self {
+            HandshakeStatus::Ok => (200, "OK"),
+            HandshakeStatus::UnknownService => (404, "Not Found"),
+            HandshakeStatus::Unauthorized => (403, "Forbidden"),
+            HandshakeStatus::BackendDown => (502, "Bad Gateway"),
+            HandshakeStatus::UnsupportedVersion
+            | HandshakeStatus::BadRequest
+            | HandshakeStatus::Unknown => (400, "Bad Request"),
+        }
+    }
+}
+
+/// Sent by the node in response to a [`StreamHandshake`].
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct HandshakeReply {
+    pub status: HandshakeStatus,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub message: Option<String>,
+}
+
+impl HandshakeReply {
+    pub fn ok() -> Self {
+        Self {
+            status: HandshakeStatus::Ok,
+            message: None,
+        }
+    }
+
+    pub fn error(status: HandshakeStatus, message: impl Into<String>) -> Self {
+        Self {
+            status,
+            message: Some(message.into()),
+        }
+    }
+}
+
+async fn write_frame<W, T>(writer: &mut W, payload: &T) -> Result<(), HandshakeError>
+where
+    W: AsyncWrite + Unpin,
+    T: Serialize,
+{
+    let body = serde_json::to_vec(payload)?;
+    if body.len() > MAX_HANDSHAKE_LEN {
+        return Err(HandshakeError::FrameTooLarge(body.len()));
+    }
+
+    let mut frame = Vec::with_capacity(5 + body.len());
+    frame.push(HANDSHAKE_VERSION);
+    frame.extend_from_slice(&(body.len() as u32).to_be_bytes());
+    frame.extend_from_slice(&body);
+    writer.write_all(&frame).await?;
+    writer.flush().await?;
+    Ok(())
+}
+
+/// Reads a frame, returning its version and payload. Payloads of unsupported versions
+/// are still consumed so the caller can reply on an intact stream.
+async fn read_frame<R: AsyncRead + Unpin>(reader: &mut R) -> Result<(u8, Vec<u8>), HandshakeError> {
+    let version = reader.read_u8().await?;
+    let len = reader.read_u32().await? as usize;
+    if len > MAX_HANDSHAKE_LEN {
+        return Err(HandshakeError::FrameTooLarge(len));
+    }
+
+    let mut body = vec![0u8; len];
+    reader.read_exact(&mut body).await?;
+    Ok((version, body))
+}
+
+pub async fn write_request<W: AsyncWrite + Unpin>(
+    writer: &mut W,
+    request: &StreamHandshake,
+) -> Result<(), HandshakeError> {
+    write_frame(writer, request).await
+}
+
+pub async fn read_request<R: AsyncRead + Unpin>(
+    reader: &mut R,
+) -> Result<StreamHandshake, HandshakeError> {
+    let (version, body) = read_frame(reader).await?;
+    if version != HANDSHAKE_VERSION {
+        return Err(HandshakeError::UnsupportedVersion(version));
+    }
+    Ok(serde_json::from_slice(&body)?)
+}
+
+pub async fn write_reply<W: AsyncWrite + Unpin>(
+    writer: &mut W,
+    reply: &HandshakeReply,
+) -> Result<(), HandshakeError> {
+    write_frame(writer, reply).await
+}
+
+pub async fn read_reply<R: AsyncRead + Unpin>(
+    reader: &mut R,
+) -> Result<HandshakeReply, HandshakeError> {
+    let (version, body) = read_frame(reader).await?;
+    if version != HANDSHAKE_VERSION {
+        return Err(HandshakeError::UnsupportedVersion(version));
+    }
+    Ok(serde_json::from_slice(&body)?)
+}
+
+/// Client side of the handshake: request a service and wait for the node to accept it.
+pub async fn request_service<S: AsyncRead + AsyncWrite + Unpin>(
+    stream: &mut S,
+    request: &StreamHandshake,
+) -> Result<(), HandshakeError> {
+    write_request(stream, request).await?;
+    let reply = read_reply(stream).await?;
+    match reply.status {
+        HandshakeStatus::Ok => Ok(()),
+        status => Err(HandshakeError::Rejected {
+            status,
+            message: reply.message.unwrap_or_default(),
+        }),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[tokio::test]
+    async fn test_request_roundtrip() {
+        let mut request = StreamHandshake::new("demo3001", "http");
+        request.auth_token = Some("token".to_string());
+        request
+            .metadata
+            .insert("client".to_string(), "test".to_string());
+
+        let mut buf = Vec::new();
+        write_request(&mut buf, &request).await.unwrap();
+        assert_eq!(buf[0], HANDSHAKE_VERSION);
+
+        let decoded = read_request(&mut buf.as_slice()).await.unwrap();
+        assert_eq!(decoded, request);
+    }
+
+    #[tokio::test]
+    async fn test_unknown_fields_and_statuses_are_tolerated() {
+        let body = br#"{"status":"some_future_status","message":"hi","extra":1}"#;
+        let mut frame = vec![HANDSHAKE_VERSION];
+        frame.extend_from_slice(&(body.len() as u32).to_be_bytes());
+        frame.extend_from_slice(body);
+
+        let reply = read_reply(&mut frame.as_slice()).await.unwrap();
+        assert_eq!(reply.status, HandshakeStatus::Unknown);
+        assert_eq!(reply.message.as_deref(), Some("hi"));
+    }
+
+    #[tokio::test]
+    async fn test_rejects_unsupported_version_and_oversized_frames() {
+        let frame = [HANDSHAKE_VERSION + 1, 0, 0, 0, 2, b'{', b'}'];
+        assert!(matches!(
+            read_request(&mut frame.as_slice()).await,
+            Err(HandshakeError::UnsupportedVersion(_))
+        ));
+
+        let frame = [HANDSHAKE_VERSION, 0xff, 0xff, 0xff, 0xff];
+        assert!(matches!(
+            read_request(&mut frame.as_slice()).await,
+            Err(HandshakeError::FrameTooLarge(_))
+        ));
+    }
+
+    #[tokio::test]
+    async fn test_request_service_reports_rejection() {
+        let (mut client, mut server) = tokio::io::duplex(1024);
+        tokio::spawn(async move {
+            let request = read_request(&mut server).await.unwrap();
+            let reply = HandshakeReply::error(
+                HandshakeStatus::UnknownService,
+                format!("unknown service: {}", request.service_id),
+            );
+            write_reply(&mut server, &reply).await.unwrap();
+        });
+
+        let err = request_service(&mut client, &StreamHandshake::new("missing", "http"))
+            .await
+            .unwrap_err();
+        assert!(matches!(
+            err,
+            HandshakeError::Rejected {
+                status: HandshakeStatus::UnknownService,
+                ..
+            }
+        ));
+    }
+}
@@ -1,8 +1,17 @@
 use anyhow::Result;
 use app_host::ServiceRpc;
 use async_trait::async_trait;
+use common::iroh_utils::AsyncStream;
+use forward::StreamContext;
 use std::collections::HashMap;
 use std::fmt::Debug;
+use tokio::net::TcpStream;
+
+pub mod dispatch;
+pub mod forward;
+pub mod handshake;
+pub mod routing;
+pub mod tracker;
 
 pub const SYNEROYM_ALPN: &[u8] = b"syneroym/1.0";
 
@@ -13,4 +22,15 @@ pub trait ProtocolHandler: Send + Sync + Debug {
 
     /// Setup the handler with the necessary services and their RPC interfaces
     async fn setup(&self, services: HashMap<String, ServiceRpc>) -> Result<()>;
+
+    /// Forward an accepted service stream to its backend. Handlers that understand the
+    /// protocol can inspect or rewrite the traffic, the default copies it unchanged.
+    async fn forward(
+        &self,
+        client: Box<dyn AsyncStream>,
+        backend: TcpStream,
+        ctx: &StreamContext,
+    ) -> Result<()> {
+        forward::forward_raw(client, backend, ctx).await
+    }
 }
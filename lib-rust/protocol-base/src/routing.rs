@@ -0,0 +1,272 @@
+use crate::handshake::HandshakeStatus;
+use common::capability::CapabilityClaims;
+use std::collections::HashMap;
+use std::sync::{Arc, RwLock};
+use store_interface::ServiceRecord;
+use thiserror::Error;
+
+#[derive(Debug, Error)]
+pub enum RouteError {
+    #[error("unknown service: {0}")]
+    UnknownService(String),
+    #[error("service {0} has no backend target configured")]
+    NoBackend(String),
+    #[error("peer {caller} is not allowed to use service {service}")]
+    Unauthorized { service: String, caller: String },
+}
+
+impl RouteError {
+    /// Handshake status reported to the client for this error.
+    pub fn status(&self) -> HandshakeStatus {
+        match self {
+            RouteError::UnknownService(_) => HandshakeStatus::UnknownService,
+            RouteError::NoBackend(_) => HandshakeStatus::BackendDown,
+            RouteError::Unauthorized { .. } => HandshakeStatus::Unauthorized,
+        }
+    }
+}
+
+/// The party opening a stream, as far as the transport can tell.
+#[derive(Debug, Clone, Default)]
+pub struct Caller {
+    /// Verified remote EndpointId, if the transport authenticates peers.
+    pub endpoint_id: Option<String>,
+    /// Whether the stream was opened by this node itself.
+    pub is_owner: bool,
+    /// Verified capability token presented in the handshake, if any.
+    pub capability: Option<CapabilityClaims>,
+}
+
+impl Caller {
+    pub fn peer(endpoint_id: impl Into<String>, is_owner: bool) -> Self {
+        Self {
+            endpoint_id: Some(endpoint_id.into()),
+            is_owner,
+            capability: None,
+        }
+    }
+
+    /// Attach a capability token that has already been verified by the transport.
+    pub fn with_capability(mut self, claims: CapabilityClaims) -> Self {
+        self.capability = Some(claims);
+        self
+    }
+
+    /// A caller whose identity the transport cannot verify.
+    pub fn anonymous() -> Self {
+        Self::default()
+    }
+}
+
+impl std::fmt::Display for Caller {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match &self.endpoint_id {
+            Some(id) => write!(f, "{}", id),
+            None => write!(f, "<anonymous>"),
+        }
+    }
+}
+
+/// A resolved route for an incoming service stream.
+#[derive(Debug, Clone)]
+pub struct ServiceRoute {
+    pub service: ServiceRecord,
+    /// Backend address (host:port) the stream should be forwarded to.
+    pub backend_addr: String,
+}
+
+/// Service name -> backend routing table shared by all transports.
+///
+/// Cloning is cheap and all clones observe the same routes.
+#[derive(Debug, Clone, Default)]
+pub struct RoutingTable {
+    services: Arc<RwLock<HashMap<String, ServiceRecord>>>,
+}
+
+impl RoutingTable {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn from_services(services: &[ServiceRecord]) -> Self {
+        let table = Self::new();
+        for service in services {
+            table.insert(service.clone());
+        }
+        table
+    }
+
+    /// Add or replace the route for a service.
+    pub fn insert(&self, service: ServiceRecord) {
+        self.services
+            .write()
+            .unwrap()
+            .insert(service.service_key.clone(), service);
+    }
+
+    /// Replace all routes at once, dropping services that are no longer listed.
+    pub fn replace_all(&self, services: &[ServiceRecord]) {
+        let services = services
+            .iter()
+            .map(|service| (service.service_key.clone(), service.clone()))
+            .collect();
+        *self.services.write().unwrap() = services;
+    }
+
+    /// Look up the backend for the given service name.
+    pub fn resolve(&self, service_key: &str) -> Result<ServiceRoute, RouteError> {
+        let services = self.services.read().unwrap();
+        let service = services
+            .get(service_key)
+            .filter(|service| service.enabled)
+            .ok_or_else(|| RouteError::UnknownService(service_key.to_string()))?;
+        let backend_addr = service
+            .backend_target
+            .clone()
+            .ok_or_else(|| RouteError::NoBackend(service_key.to_string()))?;
+
+        Ok(ServiceRoute {
+            service: service.clone(),
+            backend_addr,
+        })
+    }
+
+    /// Look up the backend for a service on behalf of `caller`, enforcing the service's
+    /// access policy before anything is dialed. A capability token for the service
+    /// stands in for the policy's mode, but never lifts the deny list.
+    pub fn resolve_for(
+        &self,
+        service_key: &str,
+        caller: &Caller,
+    ) -> Result<ServiceRoute, RouteError> {
+        let route = self.resolve(service_key)?;
+        let policy = &route.service.access_policy;
+        let caller_id = caller.endpoint_id.as_deref();
+        let delegated = caller
+            .capability
+            .as_ref()
+            .is_some_and(|claims| claims.service == service_key);
+        let permitted = if delegated {
+            !policy.denies(caller_id)
+        } else {
+            policy.permits(caller_id, caller.is_owner)
+        };
+        if !permitted {
+            return Err(RouteError::Unauthorized {
+                service: service_key.to_string(),
+                caller: caller.to_string(),
+            });
+        }
+        Ok(route)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use store_interface::AccessMode;
+
+    fn service(key: &str, backend: Option<&str>) -> ServiceRecord {
+        ServiceRecord {
+            service_key: key.to_string(),
+            app_layer_protocol: "http".to_string(),
+            service_image_manifest_ref: format!("local-http/{}", key),
+            backend_target: backend.map(|b| b.to_string()),
+            access_policy: Default::default(),
+            enabled: true,
+            identity_headers: false,
+        }
+    }
+
+    #[test]
+    fn test_resolve_routes() {
+        let table = RoutingTable::from_services(&[
+            service("demo3001", Some("127.0.0.1:3001")),
+            service("nobackend", None),
+        ]);
+
+        let route = table.resolve("demo3001").unwrap();
+        assert_eq!(route.backend_addr, "127.0.0.1:3001");
+        assert!(matches!(
+            table.resolve("nobackend"),
+            Err(RouteError::NoBackend(_))
+        ));
+        assert!(matches!(
+            table.resolve("missing"),
+            Err(RouteError::UnknownService(_))
+        ));
+    }
+
+    #[test]
+    fn test_resolve_for_enforces_access_policy() {
+        let mut private = service("private", Some("127.0.0.1:3001"));
+        private.access_policy.mode = AccessMode::AllowList;
+        private.access_policy.allowed_peers = vec!["friend".to_string()];
+        let table = RoutingTable::from_services(&[private]);
+
+        assert!(
+            table
+                .resolve_for("private", &Caller::peer("friend", false))
+                .is_ok()
+        );
+        assert!(
+            table
+                .resolve_for("private", &Caller::peer("me", true))
+                .is_ok()
+        );
+        assert!(matches!(
+            table.resolve_for("private", &Caller::peer("stranger", false)),
+            Err(RouteError::Unauthorized { .. })
+        ));
+        assert!(matches!(
+            table.resolve_for("private", &Caller::anonymous()),
+            Err(RouteError::Unauthorized { .. })
+        ));
+    }
+
+    #[test]
+    fn test_capability_grants_access_but_not_past_deny_list() {
+        let mut private = service("private", Some("127.0.0.1:3001"));
+        private.access_policy.mode = AccessMode::OwnerOnly;
+        private.access_policy.denied_peers = vec!["banned".to_string()];
+        let table = RoutingTable::from_services(&[private]);
+        let claims = |service: &str| CapabilityClaims {
+            issuer: "me".to_string(),
+            subject: None,
+            holder: None,
+            service: service.to_string(),
+            expires_at: u64::MAX,
+            methods: vec![],
+        };
+
+        let guest = Caller::peer("guest", false).with_capability(claims("private"));
+        assert!(table.resolve_for("private", &guest).is_ok());
+
+        let other = Caller::peer("guest", false).with_capability(claims("other"));
+        assert!(table.resolve_for("private", &other).is_err());
+
+        let banned = Caller::peer("banned", false).with_capability(claims("private"));
+        assert!(table.resolve_for("private", &banned).is_err());
+    }
+
+    #[test]
+    fn test_insert_is_shared_between_clones() {
+        let table = RoutingTable::new();
+        let clone = table.clone();
+        clone.insert(service("late", Some("127.0.0.1:4000")));
+        assert!(table.resolve("late").is_ok());
+    }
+
+    #[test]
+    fn test_replace_all_drops_removed_services() {
+        let table = RoutingTable::from_services(&[service("old", Some("127.0.0.1:1"))]);
+        let shared = table.clone();
+        table.replace_all(&[service("new", Some("127.0.0.1:2"))]);
+
+        assert!(matches!(
+            shared.resolve("old"),
+            Err(RouteError::UnknownService(_))
+        ));
+        assert_eq!(shared.resolve("new").unwrap().backend_addr, "127.0.0.1:2");
+    }
+}
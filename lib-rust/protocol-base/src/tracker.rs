@@ -0,0 +1,241 @@
+//! Bookkeeping of the peer connections served by this node, and of the service streams
+//! running over them, whichever transport carried them.
+
+use common::metrics::ByteCounters;
+use iroh::endpoint::Connection;
+use iroh::{Endpoint, Watcher};
+use serde::{Deserialize, Serialize};
+use std::cmp::Reverse;
+use std::collections::HashMap;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{Arc, Mutex};
+use std::time::Instant;
+
+/// An incoming peer connection, as reported by [`ConnectionTracker::connections`].
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct ConnectionInfo {
+    pub remote_id: String,
+    /// `direct(..)`, `relay(..)`, `mixed(..)` or `none` for iroh peers, the transport
+    /// for others, e.g. `webrtc`
+    pub path: String,
+    /// Zero when the transport does not measure it
+    pub rtt_ms: f64,
+    /// UDP payload bytes, QUIC overhead included, for iroh peers. The bytes of the open
+    /// streams for others
+    pub bytes_sent: u64,
+    pub bytes_received: u64,
+    pub connected_secs: u64,
+    pub streams: Vec<StreamInfo>,
+}
+
+/// A service stream running over a connection.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct StreamInfo {
+    pub service: String,
+    pub app_protocol: String,
+    /// Bytes passed on to the peer, after the handshake
+    pub bytes_sent: u64,
+    pub bytes_received: u64,
+    pub open_secs: u64,
+}
+
+#[derive(Debug)]
+enum Remote {
+    Iroh(Connection),
+    /// A peer of another transport, known by the id it registered with.
+    Other {
+        id: String,
+        transport: String,
+    },
+}
+
+#[derive(Debug)]
+struct TrackedConnection {
+    remote: Remote,
+    since: Instant,
+    streams: HashMap<u64, TrackedStream>,
+}
+
+#[derive(Debug)]
+struct TrackedStream {
+    service: String,
+    app_protocol: String,
+    since: Instant,
+    counters: Arc<ByteCounters>,
+}
+
+/// Live view of the connections accepted by the node. Cheap to clone, all clones share
+/// the same state.
+#[derive(Debug, Clone)]
+pub struct ConnectionTracker {
+    /// The iroh endpoint, if iroh is up, to tell the path of its connections.
+    endpoint: Option<Endpoint>,
+    connections: Arc<Mutex<HashMap<u64, TrackedConnection>>>,
+    next_id: Arc<AtomicU64>,
+}
+
+impl ConnectionTracker {
+    pub fn new(endpoint: Option<Endpoint>) -> Self {
+        Self {
+            endpoint,
+            connections: Default::default(),
+            next_id: Default::default(),
+        }
+    }
+
+    /// Snapshot of the open connections and their streams.
+    pub fn connections(&self) -> Vec<ConnectionInfo> {
+        let connections = self.connections.lock().unwrap();
+        let mut infos: Vec<ConnectionInfo> = connections
+            .values()
+            .map(|tracked| {
+                let mut streams: Vec<StreamInfo> = tracked
+                    .streams
+                    .values()
+                    .map(|stream| StreamInfo {
+                        service: stream.service.clone(),
+                        app_protocol: stream.app_protocol.clone(),
+                        bytes_sent: stream.counters.sent(),
+                        bytes_received: stream.counters.received(),
+                        open_secs: stream.since.elapsed().as_secs(),
+                    })
+                    .collect();
+                streams.sort_by_key(|s| Reverse(s.open_secs));
+                let connected_secs = tracked.since.elapsed().as_secs();
+                match &tracked.remote {
+                    Remote::Iroh(connection) => {
+                        let remote_id = connection.remote_id();
+                        let path = self
+                            .endpoint
+                            .as_ref()
+                            .and_then(|endpoint| endpoint.conn_type(remote_id))
+                            .map(|mut watcher| watcher.get().to_string())
+                            .unwrap_or_else(|| "none".to_string());
+                        let stats = connection.stats();
+                        ConnectionInfo {
+                            remote_id: remote_id.to_string(),
+                            path,
+                            rtt_ms: connection.rtt().as_secs_f64() * 1000.0,
+                            bytes_sent: stats.udp_tx.bytes,
+                            bytes_received: stats.udp_rx.bytes,
+                            connected_secs,
+                            streams,
+                        }
+                    }
+                    Remote::Other { id, transport } => ConnectionInfo {
+                        remote_id: id.clone(),
+                        path: transport.clone(),
+                        rtt_ms: 0.0,
+                        bytes_sent: streams.iter().map(|s| s.bytes_sent).sum(),
+                        bytes_received: streams.iter().map(|s| s.bytes_received).sum(),
+                        connected_secs,
+                        streams,
+                    },
+                }
+            })
+            .collect();
+        infos.sort_by_key(|c| Reverse(c.connected_secs));
+        infos
+    }
+
+    /// Track the iroh `connection` until the returned guard is dropped.
+    pub fn track(&self, connection: &Connection) -> ConnectionGuard {
+        self.insert(Remote::Iroh(connection.clone()))
+    }
+
+    /// Track a peer connected over `transport` as `remote_id`, until the returned guard
+    /// is dropped.
+    pub fn track_peer(&self, remote_id: &str, transport: &str) -> ConnectionGuard {
+        self.insert(Remote::Other {
+            id: remote_id.to_string(),
+            transport: transport.to_string(),
+        })
+    }
+
+    fn insert(&self, remote: Remote) -> ConnectionGuard {
+        let id = self.next_id.fetch_add(1, Ordering::Relaxed);
+        self.connections.lock().unwrap().insert(
+            id,
+            TrackedConnection {
+                remote,
+                since: Instant::now(),
+                streams: HashMap::new(),
+            },
+        );
+        ConnectionGuard {
+            handle: ConnectionHandle {
+                tracker: self.clone(),
+                id,
+            },
+        }
+    }
+}
+
+/// Removes its connection from the tracker when dropped.
+#[derive(Debug)]
+pub struct ConnectionGuard {
+    handle: ConnectionHandle,
+}
+
+impl ConnectionGuard {
+    pub fn handle(&self) -> ConnectionHandle {
+        self.handle.clone()
+    }
+}
+
+impl Drop for ConnectionGuard {
+    fn drop(&mut self) {
+        let tracker = &self.handle.tracker;
+        tracker.connections.lock().unwrap().remove(&self.handle.id);
+    }
+}
+
+/// Registers streams on a tracked connection.
+#[derive(Debug, Clone)]
+pub struct ConnectionHandle {
+    tracker: ConnectionTracker,
+    id: u64,
+}
+
+impl ConnectionHandle {
+    /// List a stream to `service` until the returned guard is dropped, reporting the
+    /// bytes of `counters`.
+    pub fn track_stream(
+        &self,
+        service: &str,
+        app_protocol: &str,
+        counters: Arc<ByteCounters>,
+    ) -> StreamGuard {
+        let stream_id = self.tracker.next_id.fetch_add(1, Ordering::Relaxed);
+        if let Some(tracked) = self.tracker.connections.lock().unwrap().get_mut(&self.id) {
+            tracked.streams.insert(
+                stream_id,
+                TrackedStream {
+                    service: service.to_string(),
+                    app_protocol: app_protocol.to_string(),
+                    since: Instant::now(),
+                    counters,
+                },
+            );
+        }
+        StreamGuard {
+            handle: self.clone(),
+            stream_id,
+        }
+    }
+}
+
+/// Removes its stream from the tracker when dropped.
+pub struct StreamGuard {
+    handle: ConnectionHandle,
+    stream_id: u64,
+}
+
+impl Drop for StreamGuard {
+    fn drop(&mut self) {
+        let mut connections = self.handle.tracker.connections.lock().unwrap();
+        if let Some(tracked) = connections.get_mut(&self.handle.id) {
+            tracked.streams.remove(&self.stream_id);
+        }
+    }
+}
@@ -0,0 +1,478 @@
+//! HTTP-aware forwarding that tells backends who is calling.
+//!
+//! Every request head on the stream is parsed, any `X-Syneroym-*` headers sent by the
+//! client are dropped, and the caller's verified identity is added instead. Bodies are
+//! passed through unchanged, framed by `Content-Length` or chunked encoding, so several
+//! requests on a keep-alive connection are each rewritten. Responses are not touched.
+//!
+//! Streams of callers whose capability token restricts methods go through here too, so
+//! every request is checked. Their heads are only rewritten if the service asked for
+//! identity headers.
+
+use anyhow::{Result, anyhow, bail};
+use common::iroh_utils::AsyncStream;
+use protocol_base::forward::{StreamContext, http_error_response};
+use tokio::io::{AsyncBufReadExt, AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt, BufReader};
+use tokio::net::TcpStream;
+use tracing::{debug, warn};
+
+/// Verified EndpointId of the calling peer.
+pub const PEER_ID_HEADER: &str = "X-Syneroym-Peer-Id";
+/// Subject of the capability token the caller presented.
+pub const TOKEN_SUBJECT_HEADER: &str = "X-Syneroym-Token-Subject";
+
+const HEADER_PREFIX: &str = "x-syneroym-";
+const MAX_HEAD_LEN: usize = 64 * 1024;
+const MAX_HEADERS: usize = 100;
+
+/// How the body following a request head is delimited.
+enum Body {
+    None,
+    Length(u64),
+    Chunked,
+    /// The connection switches protocols (or tunnels), everything after the head is
+    /// opaque.
+    Opaque,
+}
+
+struct RequestHead {
+    method: String,
+    bytes: Vec<u8>,
+    body: Body,
+}
+
+/// Read one request head, up to and including the empty line. Returns `None` when the
+/// client closed the connection between requests.
+async fn read_head<R: AsyncRead + Unpin>(reader: &mut BufReader<R>) -> Result<Option<Vec<u8>>> {
+    let mut head = Vec::new();
+    loop {
+        let start = head.len();
+        let n = (&mut *reader)
+            .take((MAX_HEAD_LEN - start) as u64 + 1)
+            .read_until(b'\n', &mut head)
+            .await?;
+        if n == 0 {
+            if head.is_empty() {
+                return Ok(None);
+            }
+            bail!("connection closed in the middle of a request head");
+        }
+        if head.len() > MAX_HEAD_LEN {
+            bail!("request head larger than {} bytes", MAX_HEAD_LEN);
+        }
+        let line = &head[start..];
+        if line == b"\r\n" || line == b"\n" {
+            // Tolerate stray empty lines between requests (RFC 9112 section 2.2)
+            if start == 0 {
+                head.clear();
+                continue;
+            }
+            return Ok(Some(head));
+        }
+    }
+}
+
+/// Rewrite a raw request head for the backend.
+fn rewrite_head(raw: &[u8], ctx: &StreamContext) -> Result<RequestHead> {
+    let mut headers = [httparse::EMPTY_HEADER; MAX_HEADERS];
+    let mut request = httparse::Request::new(&mut headers);
+    match request.parse(raw)? {
+        httparse::Status::Complete(_) => {}
+        httparse::Status::Partial => bail!("incomplete request head"),
+    }
+    let method = request.method.unwrap_or_default().to_string();
+    let path = request.path.unwrap_or_default();
+    let version = request.version.unwrap_or(1);
+
+    let mut bytes = format!("{} {} HTTP/1.{}\r\n", method, path, version).into_bytes();
+    let body = body_framing(request.headers)?;
+    let mut upgrade = method.eq_ignore_ascii_case("CONNECT");
+    let identity = ctx.service.identity_headers;
+    for header in request.headers.iter() {
+        let name = header.name;
+        if identity
+            && name.len() >= HEADER_PREFIX.len()
+            && name[..HEADER_PREFIX.len()].eq_ignore_ascii_case(HEADER_PREFIX)
+        {
+            warn!("Dropping client supplied header {}", name);
+            continue;
+        }
+        // A chunked body is never announced with a length as well
+        if name.eq_ignore_ascii_case("content-length") && matches!(body, Body::Chunked) {
+            continue;
+        }
+        if name.eq_ignore_ascii_case("upgrade") {
+            upgrade = true;
+        }
+
+        bytes.extend_from_slice(name.as_bytes());
+        bytes.extend_from_slice(b": ");
+        bytes.extend_from_slice(header.value);
+        bytes.extend_from_slice(b"\r\n");
+    }
+
+    if identity && let Some(peer) = &ctx.caller.endpoint_id {
+        bytes.extend_from_slice(format!("{}: {}\r\n", PEER_ID_HEADER, peer).as_bytes());
+    }
+    if identity
+        && let Some(subject) = ctx
+            .caller
+            .capability
+            .as_ref()
+            .and_then(|c| c.subject.as_ref())
+    {
+        bytes.extend_from_slice(format!("{}: {}\r\n", TOKEN_SUBJECT_HEADER, subject).as_bytes());
+    }
+    bytes.extend_from_slice(b"\r\n");
+
+    Ok(RequestHead {
+        method,
+        bytes,
+        body: if upgrade { Body::Opaque } else { body },
+    })
+}
+
+/// Work out how the body is delimited, refusing heads a backend could read differently
+/// (RFC 9112 section 6.3): `Transfer-Encoding` together with `Content-Length`, or
+/// `Content-Length` values that disagree.
+fn body_framing(headers: &[httparse::Header]) -> Result<Body> {
+    let mut chunked = false;
+    let mut transfer_encoding = false;
+    let mut length = None;
+    for header in headers {
+        let value = String::from_utf8_lossy(header.value);
+        if header.name.eq_ignore_ascii_case("transfer-encoding") {
+            transfer_encoding = true;
+            chunked |= value.to_ascii_lowercase().contains("chunked");
+        } else if header.name.eq_ignore_ascii_case("content-length") {
+            for value in value.split(',') {
+                let len: u64 = value
+                    .trim()
+                    .parse()
+                    .map_err(|_| anyhow!("invalid Content-Length: {}", value))?;
+                if length.is_some_and(|length| length != len) {
+                    bail!("conflicting Content-Length values");
+                }
+                length = Some(len);
+            }
+        }
+    }
+    if transfer_encoding && length.is_some() {
+        bail!("both Transfer-Encoding and Content-Length are set");
+    }
+    Ok(match length {
+        _ if chunked => Body::Chunked,
+        Some(len) => Body::Length(len),
+        None => Body::None,
+    })
+}
+
+/// Copy a chunked body, including the terminating chunk and trailers.
+async fn copy_chunked<R, W>(reader: &mut BufReader<R>, writer: &mut W) -> Result<()>
+where
+    R: AsyncRead + Unpin,
+    W: AsyncWrite + Unpin,
+{
+    loop {
+        let mut line = Vec::new();
+        (&mut *reader)
+            .take(1024)
+            .read_until(b'\n', &mut line)
+            .await?;
+        if !line.ends_with(b"\n") {
+            bail!("invalid chunk size line");
+        }
+        writer.write_all(&line).await?;
+
+        let size = String::from_utf8_lossy(&line);
+        let size = size.split(';').next().unwrap_or_default().trim();
+        let size = u64::from_str_radix(size, 16).map_err(|_| anyhow!("invalid chunk size"))?;
+        if size == 0 {
+            // Trailers, up to the final empty line
+            loop {
+                line.clear();
+                (&mut *reader)
+                    .take(MAX_HEAD_LEN as u64)
+                    .read_until(b'\n', &mut line)
+                    .await?;
+                if line.is_empty() {
+                    bail!("connection closed in chunked trailers");
+                }
+                writer.write_all(&line).await?;
+                if line == b"\r\n" || line == b"\n" {
+                    return Ok(());
+                }
+            }
+        }
+
+        // Chunk data and its CRLF
+        let expected = size + 2;
+        let copied = tokio::io::copy(&mut (&mut *reader).take(expected), writer).await?;
+        if copied != expected {
+            bail!("connection closed in the middle of a chunk");
+        }
+    }
+}
+
+/// Forward an HTTP/1.x stream request by request, checking each method against the
+/// caller's capability token and adding the caller's identity if the service wants it.
+pub async fn forward_with_identity(
+    client: Box<dyn AsyncStream>,
+    backend: TcpStream,
+    ctx: &StreamContext,
+) -> Result<()> {
+    let (client_read, mut client_write) = tokio::io::split(client);
+    let (mut backend_read, mut backend_write) = backend.into_split();
+
+    // Responses flow back unchanged
+    let mut responses = tokio::spawn(async move {
+        let result = tokio::io::copy(&mut backend_read, &mut client_write).await;
+        (client_write, result)
+    });
+
+    let mut reader = BufReader::new(client_read);
+    let outcome = tokio::select! {
+        outcome = forward_requests(&mut reader, &mut backend_write, ctx) => outcome,
+        // The backend closed the connection, nothing more can be served
+        joined = &mut responses => {
+            let (mut client_write, _) = joined?;
+            let _ = client_write.shutdown().await;
+            return Ok(());
+        }
+    };
+    let _ = backend_write.shutdown().await;
+
+    let (mut client_write, copied) = responses.await?;
+    if let Err(e) = copied {
+        debug!("response copy ended: {}", e);
+    }
+    if let Err(Refusal { status, message }) = &outcome {
+        if let Some((code, reason)) = status {
+            let response = http_error_response(*code, reason, message);
+            let _ = client_write.write_all(response.as_bytes()).await;
+        }
+        let _ = client_write.shutdown().await;
+        return Err(anyhow!(message.clone()));
+    }
+    let _ = client_write.shutdown().await;
+    Ok(())
+}
+
+/// Why request forwarding stopped early, and what to tell the client.
+struct Refusal {
+    status: Option<(u16, &'static str)>,
+    message: String,
+}
+
+impl From<anyhow::Error> for Refusal {
+    fn from(e: anyhow::Error) -> Self {
+        Refusal {
+            status: None,
+            message: e.to_string(),
+        }
+    }
+}
+
+impl From<std::io::Error> for Refusal {
+    fn from(e: std::io::Error) -> Self {
+        anyhow::Error::from(e).into()
+    }
+}
+
+async fn forward_requests<R, W>(
+    reader: &mut BufReader<R>,
+    backend: &mut W,
+    ctx: &StreamContext,
+) -> Result<(), Refusal>
+where
+    R: AsyncRead + Unpin,
+    W: AsyncWrite + Unpin,
+{
+    while let Some(raw) = read_head(reader).await? {
+        let head = rewrite_head(&raw, ctx).map_err(|e| Refusal {
+            status: Some((400, "Bad Request")),
+            message: format!("malformed request: {}", e),
+        })?;
+
+        if let Some(claims) = ctx.caller.capability.as_ref()
+            && !claims.allows_method(&head.method)
+        {
+            warn!(
+                "Capability token for {} does not allow {} requests",
+                claims.service, head.method
+            );
+            return Err(Refusal {
+                status: Some((403, "Forbidden")),
+                message: format!("capability token does not allow {} requests", head.method),
+            });
+        }
+
+        backend.write_all(&head.bytes).await?;
+        match head.body {
+            Body::None => {}
+            Body::Length(len) => {
+                let copied = tokio::io::copy(&mut (&mut *reader).take(len), backend).await?;
+                if copied != len {
+                    return Err(anyhow!("connection closed in the middle of a body").into());
+                }
+            }
+            Body::Chunked => copy_chunked(reader, backend).await?,
+            Body::Opaque => {
+                tokio::io::copy(reader, backend).await?;
+                break;
+            }
+        }
+        backend.flush().await?;
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::HttpHandler;
+    use common::capability::CapabilityClaims;
+    use protocol_base::ProtocolHandler;
+    use protocol_base::routing::Caller;
+    use store_interface::ServiceRecord;
+    use tokio::net::TcpListener;
+
+    fn context(caller: Caller) -> StreamContext {
+        StreamContext {
+            service: ServiceRecord {
+                service_key: "demo3001".to_string(),
+                app_layer_protocol: "http".to_string(),
+                service_image_manifest_ref: "local-http/demo3001".to_string(),
+                backend_target: None,
+                access_policy: Default::default(),
+                enabled: true,
+                identity_headers: true,
+            },
+            caller,
+        }
+    }
+
+    /// Claims of a token for the test service, restricted to `methods` if any.
+    fn claims(subject: Option<&str>, methods: &[&str]) -> CapabilityClaims {
+        CapabilityClaims {
+            issuer: "node".to_string(),
+            subject: subject.map(str::to_string),
+            holder: None,
+            service: "demo3001".to_string(),
+            expires_at: u64::MAX,
+            methods: methods.iter().map(|m| m.to_string()).collect(),
+        }
+    }
+
+    /// Forward `request` through a backend that records what it received.
+    async fn forward(request: &[u8], ctx: StreamContext) -> (String, String) {
+        forward_via(request, ctx, false).await
+    }
+
+    /// Like [`forward`], going through [`HttpHandler`] as the dispatcher does.
+    async fn forward_through_handler(request: &[u8], ctx: StreamContext) -> (String, String) {
+        forward_via(request, ctx, true).await
+    }
+
+    async fn forward_via(request: &[u8], ctx: StreamContext, handler: bool) -> (String, String) {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        let received = tokio::spawn(async move {
+            let (mut socket, _) = listener.accept().await.unwrap();
+            let mut received = Vec::new();
+            socket.read_to_end(&mut received).await.unwrap();
+            socket
+                .write_all(b"HTTP/1.1 204 No Content\r\n\r\n")
+                .await
+                .unwrap();
+            String::from_utf8(received).unwrap()
+        });
+
+        let (mut client, remote) = tokio::io::duplex(64 * 1024);
+        let backend = TcpStream::connect(addr).await.unwrap();
+        let forwarding = tokio::spawn(async move {
+            let _ = if handler {
+                HttpHandler::new()
+                    .forward(Box::new(remote), backend, &ctx)
+                    .await
+            } else {
+                forward_with_identity(Box::new(remote), backend, &ctx).await
+            };
+        });
+
+        client.write_all(request).await.unwrap();
+        client.shutdown().await.unwrap();
+        let mut response = Vec::new();
+        client.read_to_end(&mut response).await.unwrap();
+        forwarding.await.unwrap();
+        (
+            received.await.unwrap(),
+            String::from_utf8(response).unwrap(),
+        )
+    }
+
+    #[tokio::test]
+    async fn test_injects_identity_on_every_request() {
+        let ctx = context(Caller::peer("peer1", false).with_capability(claims(Some("alice"), &[])));
+        let request = b"GET / HTTP/1.1\r\nHost: demo\r\nX-Syneroym-Peer-Id: spoofed\r\n\r\n\
+            POST /c HTTP/1.1\r\nHost: demo\r\nx-syneroym-token-subject: root\r\nContent-Length: 5\r\n\r\nhello\
+            PUT /d HTTP/1.1\r\nTransfer-Encoding: chunked\r\n\r\n3\r\nabc\r\n0\r\n\r\n";
+
+        let (received, response) = forward(request, ctx).await;
+        assert!(!received.contains("spoofed"));
+        assert!(!received.contains("root"));
+        assert_eq!(received.matches("X-Syneroym-Peer-Id: peer1\r\n").count(), 3);
+        assert_eq!(
+            received
+                .matches("X-Syneroym-Token-Subject: alice\r\n")
+                .count(),
+            3
+        );
+        assert!(received.contains("\r\n\r\nhello"));
+        assert!(received.ends_with("\r\n\r\n3\r\nabc\r\n0\r\n\r\n"));
+        assert!(response.starts_with("HTTP/1.1 204"));
+    }
+
+    #[tokio::test]
+    async fn test_refuses_methods_outside_the_token() {
+        let ctx = context(Caller::peer("peer1", false).with_capability(claims(None, &["GET"])));
+        let request = b"GET / HTTP/1.1\r\n\r\nDELETE / HTTP/1.1\r\n\r\n";
+
+        let (received, response) = forward(request, ctx).await;
+        assert!(received.starts_with("GET / HTTP/1.1\r\n"));
+        assert!(!received.contains("DELETE"));
+        assert!(response.contains("HTTP/1.1 403 Forbidden"));
+    }
+
+    #[tokio::test]
+    async fn test_checks_every_pipelined_request_without_identity_headers() {
+        let mut ctx =
+            context(Caller::peer("peer1", false).with_capability(claims(Some("alice"), &["GET"])));
+        ctx.service.identity_headers = false;
+        let request = b"GET / HTTP/1.1\r\nHost: demo\r\nX-Syneroym-Peer-Id: mine\r\n\r\n\
+            DELETE /x HTTP/1.1\r\nHost: demo\r\n\r\n";
+
+        let (received, response) = forward_through_handler(request, ctx).await;
+        assert!(received.starts_with("GET / HTTP/1.1\r\n"));
+        assert!(!received.contains("DELETE"));
+        // Without identity headers the head is passed on as the client sent it
+        assert!(received.contains("X-Syneroym-Peer-Id: mine\r\n"));
+        assert!(!received.contains("peer1"));
+        assert!(response.contains("HTTP/1.1 403 Forbidden"));
+    }
+
+    #[tokio::test]
+    async fn test_rejects_ambiguous_body_framing() {
+        for request in [
+            &b"POST / HTTP/1.1\r\nContent-Length: 3\r\nContent-Length: 5\r\n\r\nhello"[..],
+            b"POST / HTTP/1.1\r\nContent-Length: 3, 5\r\n\r\nhello",
+            b"POST / HTTP/1.1\r\nContent-Length: 5\r\nTransfer-Encoding: chunked\r\n\r\n\
+              0\r\n\r\nGET /smuggled HTTP/1.1\r\n\r\n",
+        ] {
+            let ctx = context(Caller::peer("peer1", false));
+            let (received, response) = forward(request, ctx).await;
+            assert!(received.is_empty(), "forwarded {:?}", received);
+            assert!(response.contains("HTTP/1.1 400 Bad Request"));
+        }
+    }
+}
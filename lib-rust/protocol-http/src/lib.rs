@@ -1,8 +1,13 @@
 use anyhow::Result;
 use app_host::ServiceRpc;
 use async_trait::async_trait;
+use common::iroh_utils::AsyncStream;
 use protocol_base::ProtocolHandler;
+use protocol_base::forward::{StreamContext, forward_raw};
 use std::collections::HashMap;
+use tokio::net::TcpStream;
+
+pub mod identity;
 
 #[derive(Debug)]
 pub struct HttpHandler;
@@ -29,4 +34,23 @@ impl ProtocolHandler for HttpHandler {
         println!("HTTP Handler setting up services: {:?}", services.keys());
         Ok(())
     }
+
+    async fn forward(
+        &self,
+        client: Box<dyn AsyncStream>,
+        backend: TcpStream,
+        ctx: &StreamContext,
+    ) -> Result<()> {
+        // Method restrictions are checked on every request, not just the first one
+        let restricted = ctx
+            .caller
+            .capability
+            .as_ref()
+            .is_some_and(|claims| !claims.methods.is_empty());
+        if ctx.service.identity_headers || restricted {
+            identity::forward_with_identity(client, backend, ctx).await
+        } else {
+            forward_raw(client, backend, ctx).await
+        }
+    }
 }
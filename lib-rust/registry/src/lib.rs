@@ -0,0 +1,381 @@
+//! Service registry: signed, sequence-numbered records mapping a service hash to the
+//! node hosting the service, modelled on BitTorrent BEP 44 mutable items.
+//!
+//! Records are signed by the hosting node's key, with the service key as the salt. The
+//! service hash is `sha256(publisher || salt)`, so anyone holding a record can check that
+//! it belongs to the hash it was looked up by. Backends only store and serve records,
+//! signatures and sequence numbers are checked on publish and again on resolve.
+
+use async_trait::async_trait;
+use base64::Engine;
+use base64::engine::general_purpose::URL_SAFE_NO_PAD;
+use iroh::{EndpointId, SecretKey, Signature};
+use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
+use std::collections::{BTreeMap, HashMap};
+use std::sync::{Arc, Mutex};
+use thiserror::Error;
+use tracing::debug;
+
+pub mod memory;
+
+#[derive(Debug, Error)]
+pub enum RegistryError {
+    #[error("malformed record: {0}")]
+    Malformed(String),
+    #[error("record has an invalid signature")]
+    BadSignature,
+    #[error("record does not match service hash {0}")]
+    HashMismatch(String),
+    #[error("stale record: sequence {seq} is older than {current}")]
+    StaleSequence { seq: u64, current: u64 },
+    #[error("registry backend error: {0}")]
+    Backend(#[from] anyhow::Error),
+}
+
+/// What a record says about a service.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct ServiceAnnouncement {
+    pub service_key: String,
+    /// EndpointId of the node hosting the service.
+    pub node_id: String,
+    pub app_protocol: String,
+    /// Free-form key/value pairs, e.g. the service image manifest.
+    #[serde(default, skip_serializing_if = "BTreeMap::is_empty")]
+    pub metadata: BTreeMap<String, String>,
+    /// Set on the tombstone published when the node stops hosting the service.
+    #[serde(default, skip_serializing_if = "std::ops::Not::not")]
+    pub withdrawn: bool,
+}
+
+/// A signed record, as stored by backends.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct SignedRecord {
+    /// EndpointId of the publishing node.
+    pub publisher: String,
+    /// The service key, distinguishing the publisher's records.
+    pub salt: String,
+    /// Increases with every update, backends never replace a record with an older one.
+    pub seq: u64,
+    /// JSON encoded [`ServiceAnnouncement`], kept verbatim so the signature stays checkable.
+    pub value: String,
+    /// base64url ed25519 signature over the BEP 44 encoding of salt, seq and value.
+    pub signature: String,
+}
+
+/// Registry key of the record `publisher` publishes under `salt`, hex encoded.
+pub fn service_hash(publisher: &EndpointId, salt: &str) -> String {
+    let mut hasher = Sha256::new();
+    hasher.update(publisher.as_bytes());
+    hasher.update(salt.as_bytes());
+    data_encoding::HEXLOWER.encode(&hasher.finalize())
+}
+
+/// The bencoded `salt`, `seq` and `v` fields a BEP 44 signature covers.
+fn signable(salt: &str, seq: u64, value: &str) -> Vec<u8> {
+    format!(
+        "4:salt{}:{}3:seqi{}e1:v{}:{}",
+        salt.len(),
+        salt,
+        seq,
+        value.len(),
+        value
+    )
+    .into_bytes()
+}
+
+impl SignedRecord {
+    /// Sign `announcement` as the node owning `secret_key`.
+    pub fn sign(
+        secret_key: &SecretKey,
+        announcement: &ServiceAnnouncement,
+        seq: u64,
+    ) -> Result<Self, RegistryError> {
+        let value = serde_json::to_string(announcement)
+            .map_err(|e| RegistryError::Malformed(e.to_string()))?;
+        let salt = announcement.service_key.clone();
+        let signature = secret_key.sign(&signable(&salt, seq, &value));
+        Ok(Self {
+            publisher: secret_key.public().to_string(),
+            salt,
+            seq,
+            value,
+            signature: URL_SAFE_NO_PAD.encode(signature.to_bytes()),
+        })
+    }
+
+    fn publisher_id(&self) -> Result<EndpointId, RegistryError> {
+        self.publisher
+            .parse()
+            .map_err(|_| RegistryError::Malformed(format!("invalid publisher {}", self.publisher)))
+    }
+
+    /// Registry key this record belongs under.
+    pub fn service_hash(&self) -> Result<String, RegistryError> {
+        Ok(service_hash(&self.publisher_id()?, &self.salt))
+    }
+
+    /// Check the signature and return the announcement the record carries.
+    pub fn verify(&self) -> Result<ServiceAnnouncement, RegistryError> {
+        let publisher = self.publisher_id()?;
+        let signature: [u8; Signature::LENGTH] = URL_SAFE_NO_PAD
+            .decode(&self.signature)
+            .ok()
+            .and_then(|bytes| bytes.try_into().ok())
+            .ok_or(RegistryError::BadSignature)?;
+        publisher
+            .verify(
+                &signable(&self.salt, self.seq, &self.value),
+                &Signature::from_bytes(&signature),
+            )
+            .map_err(|_| RegistryError::BadSignature)?;
+
+        let announcement: ServiceAnnouncement = serde_json::from_str(&self.value)
+            .map_err(|e| RegistryError::Malformed(e.to_string()))?;
+        if announcement.service_key != self.salt {
+            return Err(RegistryError::Malformed(
+                "announcement is for another service".to_string(),
+            ));
+        }
+        if !announcement.node_id.eq_ignore_ascii_case(&self.publisher) {
+            return Err(RegistryError::Malformed(
+                "announcement names another hosting node".to_string(),
+            ));
+        }
+        Ok(announcement)
+    }
+
+    /// Whether this record may replace `current`. Re-publishing the same sequence number
+    /// is only accepted for an identical value.
+    pub fn check_supersedes(&self, current: Option<&SignedRecord>) -> Result<(), RegistryError> {
+        match current {
+            Some(current)
+                if self.seq < current.seq
+                    || (self.seq == current.seq && self.value != current.value) =>
+            {
+                Err(RegistryError::StaleSequence {
+                    seq: self.seq,
+                    current: current.seq,
+                })
+            }
+            _ => Ok(()),
+        }
+    }
+}
+
+/// Storage for signed records, keyed by service hash.
+///
+/// Backends must only accept records that verify and supersede the one they hold.
+#[async_trait]
+pub trait RegistryBackend: Send + Sync {
+    /// Store a record under its service hash, replacing the previous one.
+    async fn put(&self, record: SignedRecord) -> Result<(), RegistryError>;
+    /// Fetch the latest record for a service hash.
+    async fn get(&self, service_hash: &str) -> Result<Option<SignedRecord>, RegistryError>;
+}
+
+/// Publishes and resolves service records through a backend.
+///
+/// Cloning is cheap, clones share the backend and the sequence numbers seen so far.
+#[derive(Clone)]
+pub struct Registry {
+    backend: Arc<dyn RegistryBackend>,
+    /// Highest sequence number resolved per service hash, so a backend cannot roll a
+    /// record back to an older version.
+    seen: Arc<Mutex<HashMap<String, u64>>>,
+}
+
+impl Registry {
+    pub fn new(backend: Arc<dyn RegistryBackend>) -> Self {
+        Self {
+            backend,
+            seen: Arc::default(),
+        }
+    }
+
+    /// Publish (or update) the record for a service, returning its service hash.
+    pub async fn publish(
+        &self,
+        secret_key: &SecretKey,
+        announcement: &ServiceAnnouncement,
+    ) -> Result<String, RegistryError> {
+        let hash = service_hash(&secret_key.public(), &announcement.service_key);
+        let seq = match self.backend.get(&hash).await? {
+            Some(current) => current.seq + 1,
+            None => 1,
+        };
+
+        let record = SignedRecord::sign(secret_key, announcement, seq)?;
+        self.backend.put(record).await?;
+        debug!(
+            "Published service {} as {} (seq {})",
+            announcement.service_key, hash, seq
+        );
+        Ok(hash)
+    }
+
+    /// Withdraw a published service by superseding its record with a tombstone, returning
+    /// its service hash.
+    pub async fn withdraw(
+        &self,
+        secret_key: &SecretKey,
+        service_key: &str,
+    ) -> Result<String, RegistryError> {
+        let tombstone = ServiceAnnouncement {
+            service_key: service_key.to_string(),
+            node_id: secret_key.public().to_string(),
+            app_protocol: String::new(),
+            metadata: BTreeMap::new(),
+            withdrawn: true,
+        };
+        self.publish(secret_key, &tombstone).await
+    }
+
+    /// Resolve a service hash to the announcement published under it, `None` if nothing
+    /// is published or the service was withdrawn.
+    pub async fn resolve(
+        &self,
+        service_hash: &str,
+    ) -> Result<Option<ServiceAnnouncement>, RegistryError> {
+        let Some(record) = self.backend.get(service_hash).await? else {
+            return Ok(None);
+        };
+        if record.service_hash()? != service_hash {
+            return Err(RegistryError::HashMismatch(service_hash.to_string()));
+        }
+        let announcement = record.verify()?;
+
+        let mut seen = self.seen.lock().unwrap();
+        let current = seen.entry(service_hash.to_string()).or_default();
+        if record.seq < *current {
+            return Err(RegistryError::StaleSequence {
+                seq: record.seq,
+                current: *current,
+            });
+        }
+        *current = record.seq;
+        Ok(Some(announcement).filter(|a| !a.withdrawn))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::memory::MemoryBackend;
+
+    fn announcement(key: &SecretKey, app_protocol: &str) -> ServiceAnnouncement {
+        ServiceAnnouncement {
+            service_key: "demo3001".to_string(),
+            node_id: key.public().to_string(),
+            app_protocol: app_protocol.to_string(),
+            metadata: BTreeMap::new(),
+            withdrawn: false,
+        }
+    }
+
+    #[tokio::test]
+    async fn test_publish_and_resolve() {
+        let key = SecretKey::generate(&mut rand::rng());
+        let backend = Arc::new(MemoryBackend::new());
+        let registry = Registry::new(backend.clone());
+
+        let hash = registry
+            .publish(&key, &announcement(&key, "http"))
+            .await
+            .unwrap();
+        assert_eq!(hash, service_hash(&key.public(), "demo3001"));
+
+        // Updates bump the sequence number
+        registry
+            .publish(&key, &announcement(&key, "tcp"))
+            .await
+            .unwrap();
+        let resolved = registry.resolve(&hash).await.unwrap().unwrap();
+        assert_eq!(resolved.app_protocol, "tcp");
+        assert_eq!(backend.get(&hash).await.unwrap().unwrap().seq, 2);
+
+        assert!(registry.resolve("missing").await.unwrap().is_none());
+    }
+
+    #[tokio::test]
+    async fn test_withdrawn_services_no_longer_resolve() {
+        let key = SecretKey::generate(&mut rand::rng());
+        let backend = Arc::new(MemoryBackend::new());
+        let registry = Registry::new(backend.clone());
+
+        let hash = registry
+            .publish(&key, &announcement(&key, "http"))
+            .await
+            .unwrap();
+        assert_eq!(registry.withdraw(&key, "demo3001").await.unwrap(), hash);
+        assert!(registry.resolve(&hash).await.unwrap().is_none());
+        assert_eq!(backend.get(&hash).await.unwrap().unwrap().seq, 2);
+
+        // Publishing again brings it back
+        registry
+            .publish(&key, &announcement(&key, "http"))
+            .await
+            .unwrap();
+        assert!(registry.resolve(&hash).await.unwrap().is_some());
+    }
+
+    #[tokio::test]
+    async fn test_rejects_forged_and_stale_records() {
+        let key = SecretKey::generate(&mut rand::rng());
+        let backend = MemoryBackend::new();
+
+        let first = SignedRecord::sign(&key, &announcement(&key, "http"), 1).unwrap();
+        let second = SignedRecord::sign(&key, &announcement(&key, "tcp"), 2).unwrap();
+        backend.put(second.clone()).await.unwrap();
+        assert!(matches!(
+            backend.put(first.clone()).await,
+            Err(RegistryError::StaleSequence { .. })
+        ));
+
+        let mut forged = second.clone();
+        forged.seq = 3;
+        assert!(matches!(forged.verify(), Err(RegistryError::BadSignature)));
+        assert!(matches!(
+            backend.put(forged).await,
+            Err(RegistryError::BadSignature)
+        ));
+
+        // Someone else's key cannot publish for this node
+        let other = SecretKey::generate(&mut rand::rng());
+        let hijack = SignedRecord::sign(&other, &announcement(&key, "http"), 9).unwrap();
+        assert!(matches!(hijack.verify(), Err(RegistryError::Malformed(_))));
+    }
+
+    #[tokio::test]
+    async fn test_resolve_detects_rollback() {
+        /// A backend that serves whatever it was last given, without any checks.
+        struct Replay(Mutex<Option<SignedRecord>>);
+
+        #[async_trait]
+        impl RegistryBackend for Replay {
+            async fn put(&self, record: SignedRecord) -> Result<(), RegistryError> {
+                *self.0.lock().unwrap() = Some(record);
+                Ok(())
+            }
+            async fn get(&self, _: &str) -> Result<Option<SignedRecord>, RegistryError> {
+                Ok(self.0.lock().unwrap().clone())
+            }
+        }
+
+        let key = SecretKey::generate(&mut rand::rng());
+        let backend = Arc::new(Replay(Mutex::new(None)));
+        let registry = Registry::new(backend.clone());
+        let hash = service_hash(&key.public(), "demo3001");
+
+        let old = SignedRecord::sign(&key, &announcement(&key, "http"), 1).unwrap();
+        let new = SignedRecord::sign(&key, &announcement(&key, "tcp"), 2).unwrap();
+        backend.put(new).await.unwrap();
+        assert!(registry.resolve(&hash).await.unwrap().is_some());
+
+        backend.put(old).await.unwrap();
+        assert!(matches!(
+            registry.resolve(&hash).await,
+            Err(RegistryError::StaleSequence { seq: 1, current: 2 })
+        ));
+    }
+}
@@ -0,0 +1,33 @@
+use crate::{RegistryBackend, RegistryError, SignedRecord};
+use async_trait::async_trait;
+use std::collections::HashMap;
+use std::sync::Mutex;
+
+/// Registry backend that keeps records in memory, for tests and single node setups.
+#[derive(Debug, Default)]
+pub struct MemoryBackend {
+    records: Mutex<HashMap<String, SignedRecord>>,
+}
+
+impl MemoryBackend {
+    pub fn new() -> Self {
+        Self::default()
+    }
+}
+
+#[async_trait]
+impl RegistryBackend for MemoryBackend {
+    async fn put(&self, record: SignedRecord) -> Result<(), RegistryError> {
+        record.verify()?;
+        let hash = record.service_hash()?;
+
+        let mut records = self.records.lock().unwrap();
+        record.check_supersedes(records.get(&hash))?;
+        records.insert(hash, record);
+        Ok(())
+    }
+
+    async fn get(&self, service_hash: &str) -> Result<Option<SignedRecord>, RegistryError> {
+        Ok(self.records.lock().unwrap().get(service_hash).cloned())
+    }
+}
@@ -0,0 +1,128 @@
+//! Embedded iroh relay, so a deployment can run its own relay next to the signaling
+//! server instead of relying on the public ones.
+
+use anyhow::{Result, anyhow};
+use iroh_relay::server::{AccessConfig, RelayConfig, Server, ServerConfig};
+use std::net::SocketAddr;
+use tracing::info;
+
+/// A running relay. The relay stops when this is dropped.
+pub struct RelayServer {
+    server: Server,
+}
+
+impl RelayServer {
+    /// Start a plain HTTP relay on `addr`. Port 0 picks a free port.
+    pub async fn spawn(addr: SocketAddr) -> Result<Self> {
+        let config = ServerConfig::<(), ()> {
+            relay: Some(RelayConfig {
+                http_bind_addr: addr,
+                tls: None,
+                limits: Default::default(),
+                key_cache_capacity: Some(1024),
+                access: AccessConfig::Everyone,
+            }),
+            quic: None,
+            metrics_addr: None,
+        };
+        let server = Server::spawn(config).await?;
+        Ok(Self { server })
+    }
+
+    pub fn http_addr(&self) -> Option<SocketAddr> {
+        self.server.http_addr()
+    }
+
+    /// The URL nodes should use as `relay_url` to reach this relay on this host.
+    pub fn url(&self) -> Option<String> {
+        self.http_addr().map(|addr| format!("http://{}", addr))
+    }
+
+    /// Run until the relay stops.
+    pub async fn wait(mut self) -> Result<()> {
+        self.server.task_handle().await??;
+        Ok(())
+    }
+
+    pub async fn shutdown(self) -> Result<()> {
+        self.server.shutdown().await?;
+        Ok(())
+    }
+}
+
+/// Start a relay on `addr`, returning once it is listening.
+pub async fn start_server(addr: SocketAddr) -> Result<RelayServer> {
+    let relay = RelayServer::spawn(addr).await?;
+    let addr = relay
+        .http_addr()
+        .ok_or_else(|| anyhow!("relay server did not bind"))?;
+    info!("Relay server listening on {}", addr);
+    Ok(relay)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use iroh::endpoint::Connection;
+    use iroh::protocol::{AcceptError, ProtocolHandler, Router};
+    use iroh::{Endpoint, EndpointAddr, RelayMap, RelayMode, RelayUrl};
+    use std::net::Ipv4Addr;
+    use std::time::Duration;
+
+    const ALPN: &[u8] = b"syneroym/relay-test";
+
+    #[derive(Debug, Clone)]
+    struct Echo;
+
+    impl ProtocolHandler for Echo {
+        async fn accept(&self, connection: Connection) -> Result<(), AcceptError> {
+            let (mut send, mut recv) = connection.accept_bi().await?;
+            tokio::io::copy(&mut recv, &mut send)
+                .await
+                .map_err(AcceptError::from_err)?;
+            send.finish()?;
+            connection.closed().await;
+            Ok(())
+        }
+    }
+
+    async fn relay_only_endpoint(url: &RelayUrl) -> Endpoint {
+        let endpoint =
+            Endpoint::empty_builder(RelayMode::Custom(RelayMap::from_iter([url.clone()])))
+                .alpns(vec![ALPN.to_vec()])
+                .bind()
+                .await
+                .unwrap();
+        tokio::time::timeout(Duration::from_secs(10), endpoint.online())
+            .await
+            .expect("endpoint did not connect to the relay");
+        endpoint
+    }
+
+    #[tokio::test]
+    async fn test_endpoints_connect_through_relay() {
+        let relay = RelayServer::spawn((Ipv4Addr::LOCALHOST, 0).into())
+            .await
+            .unwrap();
+        let url: RelayUrl = relay.url().unwrap().parse().unwrap();
+
+        let server = relay_only_endpoint(&url).await;
+        let server_id = server.id();
+        let router = Router::builder(server).accept(ALPN, Echo).spawn();
+
+        // The only address the client knows is the relay
+        let client = relay_only_endpoint(&url).await;
+        let connection = client
+            .connect(EndpointAddr::new(server_id).with_relay_url(url), ALPN)
+            .await
+            .unwrap();
+        let (mut send, mut recv) = connection.open_bi().await.unwrap();
+        send.write_all(b"hello").await.unwrap();
+        send.finish().unwrap();
+        assert_eq!(recv.read_to_end(16).await.unwrap(), b"hello");
+
+        connection.close(0u32.into(), b"done");
+        router.shutdown().await.unwrap();
+        relay.shutdown().await.unwrap();
+    }
+}
@@ -14,7 +14,7 @@ use std::{
     sync::{Arc, Mutex},
 };
 use tokio::sync::broadcast;
-use tracing::{info, warn};
+use tracing::{debug, info, warn};
 
 // A simple signaling server state
 struct AppState {
@@ -86,9 +86,19 @@ async fn handle_socket(socket: WebSocket, state: Arc<AppState>) {
 
     // Spawn a task to forward messages from the broadcast channel to the websocket
     let send_task = tokio::spawn(async move {
-        while let Ok(msg) = rcv_ch.recv().await {
-            if ws_sink.send(Message::Text(msg)).await.is_err() {
-                break;
+        loop {
+            match rcv_ch.recv().await {
+                Ok(msg) => {
+                    if ws_sink.send(Message::Text(msg)).await.is_err() {
+                        break;
+                    }
+                }
+                // Trickled candidates come in bursts, a slow peer misses some of them
+                // rather than its whole connection
+                Err(broadcast::error::RecvError::Lagged(skipped)) => {
+                    warn!("Dropped {} messages for a slow peer", skipped);
+                }
+                Err(broadcast::error::RecvError::Closed) => break,
             }
         }
     });
@@ -96,12 +106,18 @@ async fn handle_socket(socket: WebSocket, state: Arc<AppState>) {
     // Loop to receive messages from websocket and route them
     while let Some(Ok(msg)) = ws_stream.next().await {
         if let Message::Text(text) = msg {
-            // Expect message format: { "target": "peer-id", ... }
-            if let Ok(v) = serde_json::from_str::<serde_json::Value>(&text) {
+            // Expect message format: { "target": "peer-id", ... }, offers, answers and
+            // trickled ICE candidates alike
+            if let Ok(mut v) = serde_json::from_str::<serde_json::Value>(&text) {
                 if let Some(target) = v.get("target").and_then(|t| t.as_str()) {
+                    let target = target.to_string();
+                    debug!("Relaying {} from {} to {}", v["type"], peer_id, target);
+                    // Receivers key their sessions by sender, so it is always the id this
+                    // socket registered, whatever the client wrote
+                    v["sender"] = serde_json::Value::String(peer_id.clone());
                     let peers = state.peers.lock().unwrap();
-                    if let Some(target_tx) = peers.get(target) {
-                        let _ = target_tx.send(text);
+                    if let Some(target_tx) = peers.get(&target) {
+                        let _ = target_tx.send(v.to_string());
                     } else {
                         warn!("Target peer {} not found", target);
                     }
@@ -117,8 +133,15 @@ async fn handle_socket(socket: WebSocket, state: Arc<AppState>) {
     // Cleanup
     send_task.abort();
     {
+        // A peer reconnecting before its old socket is noticed as closed registers again
+        // under the same id, its new entry stays
         let mut peers = state.peers.lock().unwrap();
-        peers.remove(&peer_id);
+        if peers
+            .get(&peer_id)
+            .is_some_and(|tx| tx.same_channel(&send_ch))
+        {
+            peers.remove(&peer_id);
+        }
     }
     info!("Peer disconnected: {}", peer_id);
 }
@@ -2,15 +2,139 @@ use anyhow::Result;
 use async_trait::async_trait;
 use serde::{Deserialize, Serialize};
 
-#[derive(Debug, Clone, Deserialize, Serialize)]
+#[derive(Debug, Clone, PartialEq, Eq, Deserialize, Serialize)]
 pub struct ServiceRecord {
     pub service_key: String,
     pub app_layer_protocol: String,
     pub service_image_manifest_ref: String,
+    /// Address (host:port) of the backend that tunnelled streams for this service are
+    /// forwarded to. Services without a backend cannot be reached over the network.
+    #[serde(default)]
+    pub backend_target: Option<String>,
+    /// Which remote peers may open streams to this service.
+    #[serde(default)]
+    pub access_policy: AccessPolicy,
+    /// Disabled services are kept in the store but neither routed nor published.
+    #[serde(default = "default_enabled")]
+    pub enabled: bool,
+    /// For HTTP services, rewrite each request to tell the backend who is calling through
+    /// `X-Syneroym-*` headers.
+    #[serde(default)]
+    pub identity_headers: bool,
+}
+
+fn default_enabled() -> bool {
+    true
+}
+
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Deserialize, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum AccessMode {
+    /// Any peer that can reach the node.
+    #[default]
+    Public,
+    /// Only the peers in [`AccessPolicy::allowed_peers`] (and the owner).
+    AllowList,
+    /// Only the node itself, e.g. through its own local proxies.
+    OwnerOnly,
+}
+
+impl AccessMode {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            AccessMode::Public => "public",
+            AccessMode::AllowList => "allow_list",
+            AccessMode::OwnerOnly => "owner_only",
+        }
+    }
+}
+
+impl std::str::FromStr for AccessMode {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self> {
+        match s {
+            "public" => Ok(AccessMode::Public),
+            "allow_list" => Ok(AccessMode::AllowList),
+            "owner_only" => Ok(AccessMode::OwnerOnly),
+            _ => Err(anyhow::anyhow!("unknown access mode: {}", s)),
+        }
+    }
+}
+
+/// Per-service access rules. Peers are identified by their EndpointId string.
+#[derive(Debug, Clone, Default, PartialEq, Eq, Deserialize, Serialize)]
+pub struct AccessPolicy {
+    #[serde(default)]
+    pub mode: AccessMode,
+    /// Peers allowed in [`AccessMode::AllowList`] mode.
+    #[serde(default)]
+    pub allowed_peers: Vec<String>,
+    /// Peers that are always refused, whatever the mode.
+    #[serde(default)]
+    pub denied_peers: Vec<String>,
+}
+
+fn listed(peers: &[String], id: &str) -> bool {
+    peers.iter().any(|p| p.eq_ignore_ascii_case(id))
+}
+
+impl AccessPolicy {
+    /// Whether the caller is on the deny list. This applies even to callers holding a
+    /// capability token for the service.
+    pub fn denies(&self, caller: Option<&str>) -> bool {
+        caller.is_some_and(|id| listed(&self.denied_peers, id))
+    }
+
+    /// Whether a caller may use the service. `caller` is the remote EndpointId when it
+    /// is known, `is_owner` tells whether the caller is this node itself.
+    pub fn permits(&self, caller: Option<&str>, is_owner: bool) -> bool {
+        if self.denies(caller) {
+            return false;
+        }
+
+        match self.mode {
+            AccessMode::Public => true,
+            AccessMode::OwnerOnly => is_owner,
+            AccessMode::AllowList => {
+                is_owner || caller.is_some_and(|id| listed(&self.allowed_peers, id))
+            }
+        }
+    }
 }
 
 #[async_trait]
 pub trait ServiceStore: Send + Sync {
     /// Retrieve all configured services.
     async fn get_services(&self) -> Result<Vec<ServiceRecord>>;
+
+    /// Add a service, or replace the service with the same key.
+    async fn upsert_service(&self, service: &ServiceRecord) -> Result<()>;
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_access_policy() {
+        let mut policy = AccessPolicy {
+            denied_peers: vec!["bad".to_string()],
+            ..Default::default()
+        };
+        assert!(policy.permits(Some("anyone"), false));
+        assert!(policy.permits(None, false));
+        assert!(!policy.permits(Some("bad"), false));
+
+        policy.mode = AccessMode::AllowList;
+        policy.allowed_peers = vec!["FRIEND".to_string()];
+        assert!(policy.permits(Some("friend"), false));
+        assert!(!policy.permits(Some("stranger"), false));
+        assert!(!policy.permits(None, false));
+        assert!(policy.permits(Some("me"), true));
+
+        policy.mode = AccessMode::OwnerOnly;
+        assert!(!policy.permits(Some("friend"), false));
+        assert!(policy.permits(Some("me"), true));
+    }
 }
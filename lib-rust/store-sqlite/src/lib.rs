@@ -3,9 +3,9 @@ use async_trait::async_trait;
 use rusqlite::Connection;
 use std::path::PathBuf;
 use std::sync::{Arc, Mutex};
-use store_interface::ServiceRecord;
 use store_interface::ServiceStore;
-use tracing::info;
+use store_interface::{AccessMode, AccessPolicy, ServiceRecord};
+use tracing::{info, warn};
 
 pub struct SqliteStore {
     // Arc<Mutex<>> is needed because rusqlite::Connection is not Sync
@@ -23,7 +23,38 @@ impl SqliteStore {
             "CREATE TABLE IF NOT EXISTS services (
                 service_key TEXT PRIMARY KEY,
                 app_layer_protocol TEXT NOT NULL,
-                service_image_manifest_ref TEXT NOT NULL
+                service_image_manifest_ref TEXT NOT NULL,
+                backend_target TEXT,
+                access_mode TEXT NOT NULL DEFAULT 'public',
+                enabled INTEGER NOT NULL DEFAULT 1,
+                identity_headers INTEGER NOT NULL DEFAULT 0
+            )",
+            [],
+        )?;
+
+        // Stores created before these columns were introduced lack them
+        add_column_if_missing(&conn, "services", "backend_target", "TEXT")?;
+        add_column_if_missing(
+            &conn,
+            "services",
+            "access_mode",
+            "TEXT NOT NULL DEFAULT 'public'",
+        )?;
+        add_column_if_missing(&conn, "services", "enabled", "INTEGER NOT NULL DEFAULT 1")?;
+        add_column_if_missing(
+            &conn,
+            "services",
+            "identity_headers",
+            "INTEGER NOT NULL DEFAULT 0",
+        )?;
+
+        // Per-service peer allow/deny entries, see `AccessPolicy`
+        conn.execute(
+            "CREATE TABLE IF NOT EXISTS service_peer_rules (
+                service_key TEXT NOT NULL,
+                endpoint_id TEXT NOT NULL,
+                rule TEXT NOT NULL CHECK (rule IN ('allow', 'deny')),
+                PRIMARY KEY (service_key, endpoint_id)
             )",
             [],
         )?;
@@ -34,26 +65,196 @@ impl SqliteStore {
     }
 }
 
+fn add_column_if_missing(
+    conn: &Connection,
+    table: &str,
+    column: &str,
+    definition: &str,
+) -> Result<()> {
+    let mut stmt = conn.prepare(&format!("PRAGMA table_info({})", table))?;
+    let exists = stmt
+        .query_map([], |row| row.get::<_, String>(1))?
+        .filter_map(|name| name.ok())
+        .any(|name| name == column);
+
+    if !exists {
+        info!("Migrating SQLite store: adding {}.{}", table, column);
+        conn.execute(
+            &format!("ALTER TABLE {} ADD COLUMN {} {}", table, column, definition),
+            [],
+        )?;
+    }
+    Ok(())
+}
+
+fn load_access_policy(conn: &Connection, service_key: &str, mode: &str) -> Result<AccessPolicy> {
+    let mode = mode.parse().unwrap_or_else(|e| {
+        // Fail closed on values we do not understand
+        warn!("Service {}: {}, treating as owner_only", service_key, e);
+        AccessMode::OwnerOnly
+    });
+    let mut policy = AccessPolicy {
+        mode,
+        ..Default::default()
+    };
+
+    let mut stmt =
+        conn.prepare("SELECT endpoint_id, rule FROM service_peer_rules WHERE service_key = ?1")?;
+    let rules = stmt.query_map([service_key], |row| {
+        Ok((row.get::<_, String>(0)?, row.get::<_, String>(1)?))
+    })?;
+    for rule in rules {
+        let (endpoint_id, rule) = rule?;
+        match rule.as_str() {
+            "allow" => policy.allowed_peers.push(endpoint_id),
+            _ => policy.denied_peers.push(endpoint_id),
+        }
+    }
+    Ok(policy)
+}
+
 #[async_trait]
 impl ServiceStore for SqliteStore {
     async fn get_services(&self) -> Result<Vec<ServiceRecord>> {
         let conn = self.conn.lock().unwrap();
         let mut stmt = conn.prepare(
-            "SELECT service_key, app_layer_protocol, service_image_manifest_ref FROM services",
+            "SELECT service_key, app_layer_protocol, service_image_manifest_ref, backend_target,
+                access_mode, enabled, identity_headers
+             FROM services",
         )?;
 
         let service_iter = stmt.query_map([], |row| {
-            Ok(ServiceRecord {
-                service_key: row.get(0)?,
-                app_layer_protocol: row.get(1)?,
-                service_image_manifest_ref: row.get(2)?,
-            })
+            Ok((
+                ServiceRecord {
+                    service_key: row.get(0)?,
+                    app_layer_protocol: row.get(1)?,
+                    service_image_manifest_ref: row.get(2)?,
+                    backend_target: row.get(3)?,
+                    access_policy: AccessPolicy::default(),
+                    enabled: row.get(5)?,
+                    identity_headers: row.get(6)?,
+                },
+                row.get::<_, String>(4)?,
+            ))
         })?;
 
         let mut services = Vec::new();
         for service in service_iter {
-            services.push(service?);
+            let (mut service, access_mode) = service?;
+            service.access_policy = load_access_policy(&conn, &service.service_key, &access_mode)?;
+            services.push(service);
         }
         Ok(services)
     }
+
+    async fn upsert_service(&self, service: &ServiceRecord) -> Result<()> {
+        let mut conn = self.conn.lock().unwrap();
+        let tx = conn.transaction()?;
+        tx.execute(
+            "INSERT INTO services (service_key, app_layer_protocol, service_image_manifest_ref,
+                backend_target, access_mode, enabled, identity_headers)
+             VALUES (?1, ?2, ?3, ?4, ?5, ?6, ?7)
+             ON CONFLICT(service_key) DO UPDATE SET
+                app_layer_protocol = excluded.app_layer_protocol,
+                service_image_manifest_ref = excluded.service_image_manifest_ref,
+                backend_target = excluded.backend_target,
+                access_mode = excluded.access_mode,
+                enabled = excluded.enabled,
+                identity_headers = excluded.identity_headers",
+            rusqlite::params![
+                service.service_key,
+                service.app_layer_protocol,
+                service.service_image_manifest_ref,
+                service.backend_target,
+                service.access_policy.mode.as_str(),
+                service.enabled,
+                service.identity_headers,
+            ],
+        )?;
+
+        tx.execute(
+            "DELETE FROM service_peer_rules WHERE service_key = ?1",
+            [&service.service_key],
+        )?;
+        let policy = &service.access_policy;
+        let rules = policy
+            .allowed_peers
+            .iter()
+            .map(|peer| (peer, "allow"))
+            .chain(policy.denied_peers.iter().map(|peer| (peer, "deny")));
+        for (peer, rule) in rules {
+            tx.execute(
+                "INSERT OR REPLACE INTO service_peer_rules VALUES (?1, ?2, ?3)",
+                [&service.service_key, peer, &rule.to_string()],
+            )?;
+        }
+        tx.commit()?;
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[tokio::test]
+    async fn test_services_load_access_policies() {
+        let store = SqliteStore::new(PathBuf::from(":memory:")).unwrap();
+        {
+            let conn = store.conn.lock().unwrap();
+            conn.execute_batch(
+                "INSERT INTO services VALUES ('open', 'http', 'local-http/open', '127.0.0.1:1', 'public', 1, 0);
+                 INSERT INTO services VALUES ('team', 'http', 'local-http/team', '127.0.0.1:2', 'allow_list', 0, 1);
+                 INSERT INTO service_peer_rules VALUES ('team', 'friend', 'allow');
+                 INSERT INTO service_peer_rules VALUES ('team', 'foe', 'deny');",
+            )
+            .unwrap();
+        }
+
+        let services = store.get_services().await.unwrap();
+        let team = services.iter().find(|s| s.service_key == "team").unwrap();
+        assert_eq!(team.access_policy.mode, AccessMode::AllowList);
+        assert_eq!(team.access_policy.allowed_peers, vec!["friend".to_string()]);
+        assert_eq!(team.access_policy.denied_peers, vec!["foe".to_string()]);
+
+        let open = services.iter().find(|s| s.service_key == "open").unwrap();
+        assert_eq!(open.access_policy.mode, AccessMode::Public);
+        assert!(open.enabled);
+        assert!(!team.enabled);
+        assert!(team.identity_headers);
+    }
+
+    #[tokio::test]
+    async fn test_upsert_service() {
+        let store = SqliteStore::new(PathBuf::from(":memory:")).unwrap();
+        let mut service = ServiceRecord {
+            service_key: "dev".to_string(),
+            app_layer_protocol: "http".to_string(),
+            service_image_manifest_ref: "local-http/dev".to_string(),
+            backend_target: Some("127.0.0.1:5173".to_string()),
+            access_policy: AccessPolicy {
+                mode: AccessMode::AllowList,
+                allowed_peers: vec!["friend".to_string()],
+                denied_peers: vec![],
+            },
+            enabled: true,
+            identity_headers: false,
+        };
+        store.upsert_service(&service).await.unwrap();
+
+        service.app_layer_protocol = "tcp".to_string();
+        service.backend_target = Some("127.0.0.1:5432".to_string());
+        service.access_policy = AccessPolicy::default();
+        store.upsert_service(&service).await.unwrap();
+
+        let services = store.get_services().await.unwrap();
+        assert_eq!(services.len(), 1);
+        assert_eq!(services[0].app_layer_protocol, "tcp");
+        assert_eq!(
+            services[0].backend_target.as_deref(),
+            Some("127.0.0.1:5432")
+        );
+        assert_eq!(services[0].access_policy.mode, AccessMode::Public);
+        assert!(services[0].access_policy.allowed_peers.is_empty());
+    }
 }